@@ -1,7 +1,7 @@
-use std::{fmt, ops::Range};
+use std::{collections::HashMap, fmt, ops::Range};
 
 use humansize::{make_format, BINARY};
-use rangemap::RangeMap;
+use rangemap::{RangeMap, RangeSet};
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
@@ -10,9 +10,39 @@ pub enum MemState {
     Resident,
     NotResident,
     Untracked,
+    /// Filled in by mevi along with a neighboring page that faulted (see
+    /// `--fault-around`): the program may never have touched it. Until it's
+    /// written to, it's backed by the shared zero page, like [MemState::ZeroPage].
+    Prefaulted,
+    /// Faulted in by a read, and not written to since (see `--track-writes`):
+    /// it's backed by the shared zero page, which costs no memory.
+    ZeroPage,
+    /// Resident, as part of a huge page: a transparent one (THP), or one from
+    /// a hugetlb mapping.
+    HugeResident,
+    /// Was resident, but the kernel swapped it out
+    Swapped,
+    /// Freed with `MADV_FREE`: still resident, until the kernel reclaims it
+    /// under memory pressure (which pagemap tells us within a second) or the
+    /// program writes to it again (which we can't see).
+    LazyFree,
+    /// Resident, and shared copy-on-write between a process and the one it
+    /// forked from (or into): it only costs memory once, until either side
+    /// writes to it.
+    SharedCow,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+impl MemState {
+    /// Whether this counts towards the resident set
+    pub fn is_resident(self) -> bool {
+        matches!(
+            self,
+            MemState::Resident | MemState::HugeResident | MemState::LazyFree | MemState::SharedCow
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(transparent)]
 pub struct TraceeId(pub u64);
 
@@ -38,10 +68,199 @@ impl From<TraceeId> for nix::unistd::Pid {
 
 pub type MemMap = RangeMap<u64, MemState>;
 
+/// The memory map of a process that just forked from one with `parent_map`:
+/// the same, except that what's resident is now shared copy-on-write.
+pub fn forked_memmap(parent_map: &MemMap) -> MemMap {
+    parent_map
+        .iter()
+        .map(|(range, state)| {
+            let state = if state.is_resident() {
+                MemState::SharedCow
+            } else {
+                *state
+            };
+            (range.clone(), state)
+        })
+        .collect()
+}
+
+/// Which thread first touched which pages, for the pages that are still there
+pub type Touches = RangeMap<u64, TraceeId>;
+
+/// What a program asked for about huge pages, with `madvise`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum HugePageAdvice {
+    #[default]
+    None,
+    /// `MADV_HUGEPAGE`: back it with transparent huge pages if possible
+    Huge,
+    /// `MADV_NOHUGEPAGE`: never back it with transparent huge pages
+    NoHuge,
+}
+
+/// Properties of a region of memory, independent of whether it's resident
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Attributes {
+    pub huge_pages: HugePageAdvice,
+    /// With `mlock` or `mlockall`, so it can't be swapped out
+    pub locked: bool,
+}
+
+/// A change to one of the [Attributes]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Attribute {
+    HugePages(HugePageAdvice),
+    Locked(bool),
+}
+
+impl Attributes {
+    pub fn set(&mut self, attr: Attribute) {
+        match attr {
+            Attribute::HugePages(advice) => self.huge_pages = advice,
+            Attribute::Locked(locked) => self.locked = locked,
+        }
+    }
+}
+
+/// Regions with default attributes aren't in there
+pub type AttrMap = RangeMap<u64, Attributes>;
+
+/// What a mapping is backed by
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MappingBacking {
+    /// Private anonymous memory
+    Anon,
+    /// Grown and shrunk with `brk`
+    Heap,
+    /// Mapped with `MAP_STACK`, or the main thread's stack
+    Stack,
+    /// A private, copy-on-write mapping of that file
+    File(String),
+    /// Shared memory: `/dev/shm`, memfd, System V, hugetlbfs, or a shared
+    /// anonymous mapping if there's no path.
+    Shm(Option<String>),
+}
+
+/// The syscall that created a mapping
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MappingOrigin {
+    Mmap,
+    Brk,
+    /// It was already there when mevi attached
+    Preexisting,
+}
+
+/// What mevi knows about a mapping, as opposed to the pages in it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Mapping {
+    pub prot: Protection,
+    /// The `MAP_*` flags it was created with, by name. For preexisting
+    /// mappings, only `MAP_SHARED` or `MAP_PRIVATE`.
+    pub flags: Vec<String>,
+    pub backing: MappingBacking,
+    pub origin: MappingOrigin,
+    /// How much of it was in transparent huge pages when mevi attached, if
+    /// it couldn't tell which pages those are (that takes
+    /// `/proc/kpageflags`). Those pages don't show up as
+    /// [MemState::HugeResident].
+    pub unplaced_huge_bytes: u64,
+}
+
+/// `PROT_*` bits
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl fmt::Display for Protection {
+    /// Like `/proc/PID/maps`, e.g. `rw-`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [(self.read, 'r'), (self.write, 'w'), (self.exec, 'x')] {
+            write!(f, "{}", if set { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Mappings mevi tracks. Adjacent mappings with the same metadata get merged,
+/// like the kernel does with VMAs.
+pub type MappingMap = RangeMap<u64, Mapping>;
+
+/// The mapping `addr` is part of, and its range
+pub fn mapping_at(mappings: &MappingMap, addr: u64) -> Option<(&Range<u64>, &Mapping)> {
+    mappings.get_key_value(&addr)
+}
+
+/// Which NUMA node resident pages are on, see `--numa`
+pub type NumaMap = RangeMap<u64, u32>;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MeviEvent {
     Snapshot(Vec<TraceeSnapshot>),
     TraceeEvent(TraceeId, TraceePayload),
+    Output(OutputChunk),
+    /// Only sent to the frontend that asked for it
+    Inspection(Inspection),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+    // stdout and stderr are indistinguishable on a pseudo-terminal
+    Pty,
+}
+
+/// Something the traced program printed, when mevi owns its stdio
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    /// Milliseconds since mevi started the traced program
+    pub elapsed_ms: u64,
+    pub data: Vec<u8>,
+}
+
+/// Sent by frontends, to look at the contents of a page
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InspectRequest {
+    pub tid: TraceeId,
+    /// Anywhere in the page
+    pub addr: u64,
+}
+
+/// The answer to an [InspectRequest]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Inspection {
+    pub tid: TraceeId,
+    /// Where the page starts
+    pub addr: u64,
+    pub page_size: u64,
+    pub result: Result<PageContents, String>,
+}
+
+/// How many pointers and strings we list in a [PageContents]
+pub const MAX_GUESSES: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageContents {
+    /// Like `hexdump -C`, with repeated lines collapsed into a `*`
+    pub hexdump: String,
+    pub zeros: bool,
+    /// Aligned words that point into a mapping, up to [MAX_GUESSES]
+    pub pointers: Vec<PointerGuess>,
+    /// Runs of printable UTF-8, 4 characters or more, up to [MAX_GUESSES]
+    pub strings: Vec<(u64, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointerGuess {
+    /// Where the word is in the page
+    pub offset: u64,
+    pub value: u64,
+    /// What it points into, as listed in `/proc/PID/maps`
+    pub mapping: String,
 }
 
 pub fn serialize_many(events: &[MeviEvent]) -> postcard::Result<Vec<u8>> {
@@ -52,24 +271,132 @@ pub fn deserialize_many(data: &[u8]) -> postcard::Result<Vec<MeviEvent>> {
     postcard::from_bytes(data)
 }
 
+pub fn serialize_request(req: &InspectRequest) -> postcard::Result<Vec<u8>> {
+    postcard::to_allocvec(req)
+}
+
+pub fn deserialize_request(data: &[u8]) -> postcard::Result<InspectRequest> {
+    postcard::from_bytes(data)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TraceeSnapshot {
     pub tid: TraceeId,
     pub cmdline: Vec<String>,
     pub map: MemMap,
+    pub degraded: Option<String>,
+    pub warnings: Vec<String>,
+    pub drift: Option<i64>,
+    pub touches: Touches,
+    /// How many bytes each thread faulted in over the process's lifetime
+    pub touched_bytes: HashMap<TraceeId, u64>,
+    pub working_set: Option<WorkingSet>,
+    pub attrs: AttrMap,
+    /// The traced process it forked from, if any
+    pub parent: Option<TraceeId>,
+    pub usage: Option<Usage>,
+    pub zero_filled: Option<ZeroFilled>,
+    pub duplicates: Option<Duplicates>,
+    pub numa_nodes: Option<NumaMap>,
+    pub mappings: MappingMap,
 }
 
+/// How much memory a process uses as the kernel counts it, from
+/// `/proc/PID/smaps_rollup`, in bytes. That's all of its memory, including
+/// what mevi doesn't track (code, file mappings, etc.), but not hugetlb pages.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Usage {
+    /// Resident set size: every resident page it maps
+    pub rss: u64,
+    /// Proportional set size: pages shared with other processes count for
+    /// their share, so adding it up over processes doesn't double count
+    pub pss: u64,
+    /// Unique set size: only the pages no other process maps
+    pub uss: u64,
+}
+
+/// Memory that was written to recently, see `--working-set`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WorkingSet {
+    /// How far back "recently" goes
+    pub window_secs: u64,
+    pub written: RangeSet<u64>,
+    /// How much of `written` is in each mapping, for mappings that have any
+    pub per_mapping: Vec<MappingBytes>,
+}
+
+/// Resident pages that only contain zeros, see `--scan-contents`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct ZeroFilled {
+    pub pages: RangeSet<u64>,
+    /// How much of that is in each mapping, for mappings that have any
+    pub per_mapping: Vec<MappingBytes>,
+}
+
+/// Resident anonymous pages that have the same contents as other pages, in
+/// the same tracee or another one, see `--scan-contents`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Duplicates {
+    pub pages: RangeSet<u64>,
+    /// Bytes that merging identical pages (like KSM does) would free in this
+    /// tracee. The copy that's kept is the one in the lowest-numbered
+    /// tracee, so this adds up across tracees.
+    pub mergeable: u64,
+    /// Bytes that are identical to other pages of this tracee
+    pub within: u64,
+    /// For every other tracee, how many bytes could be merged with its pages
+    pub with: Vec<(TraceeId, u64)>,
+    /// How much of `pages` is in each mapping, for mappings that have any
+    pub per_mapping: Vec<MappingBytes>,
+}
+
+/// A number of bytes in a mapping, as listed in `/proc/PID/maps`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MappingBytes {
+    pub range: Range<u64>,
+    /// Its path, or something like `[heap]`
+    pub name: String,
+    pub bytes: u64,
+}
+
+/// How many warnings we keep around per tracee
+pub const MAX_WARNINGS: usize = 16;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TraceePayload {
     /// Clears all memory mappings
     Exec,
 
+    /// A mapping was created, see [MemStateChange] for the state of its
+    /// pages
+    ///
+    /// [MemStateChange]: TraceePayload::MemStateChange
+    Mapped {
+        range: Range<u64>,
+        mapping: Mapping,
+    },
+
+    /// Used on mprotect
+    Protect {
+        range: Range<u64>,
+        prot: Protection,
+    },
+
     // Used on mmap, madvise(DONTNEED), page faults
     MemStateChange {
         range: Range<u64>,
         state: MemState,
     },
 
+    /// A page fault was resolved, by `thread` touching `range` first. That's
+    /// `None` if it wasn't one of the tracee's threads, but mevi reading the
+    /// page.
+    PageFault {
+        range: Range<u64>,
+        state: MemState,
+        thread: Option<TraceeId>,
+    },
+
     // Clears a specific mapping
     Unmap {
         range: Range<u64>,
@@ -85,6 +412,50 @@ pub enum TraceePayload {
         cmdline: Vec<String>,
     },
 
+    /// The tracee is traced with reduced fidelity, e.g. with only user faults
+    Degraded {
+        reason: String,
+    },
+
+    /// Something went wrong for the tracee, because of the way it's traced
+    Warning {
+        message: String,
+    },
+
+    /// How many more bytes we think are resident than the kernel does
+    /// (negative if fewer), so how far off the memory map is.
+    Drift {
+        bytes: i64,
+    },
+
+    /// The tracee forked from `parent`, which is traced too: its memory map
+    /// starts out as the parent's, see [forked_memmap].
+    Forked {
+        parent: TraceeId,
+    },
+
+    /// Replaces the tracee's memory usage, as the kernel counts it
+    Usage(Usage),
+
+    /// Replaces what we know of the tracee's all-zero pages
+    ZeroFilled(ZeroFilled),
+
+    /// Replaces what we know of the tracee's duplicate pages
+    Duplicates(Duplicates),
+
+    /// Replaces which NUMA node the tracee's resident pages are on
+    NumaNodes(NumaMap),
+
+    /// Replaces the tracee's working set
+    WorkingSet(WorkingSet),
+
+    /// Used on madvise hints and mlock, which don't change what's resident
+    /// by themselves
+    SetAttribute {
+        range: Range<u64>,
+        attr: Attribute,
+    },
+
     Exit,
 }
 
@@ -100,7 +471,8 @@ impl TraceePayload {
                 // all the mappings are invalidated on exec
                 map.clear();
             }
-            TraceePayload::MemStateChange { range, state } => {
+            TraceePayload::MemStateChange { range, state }
+            | TraceePayload::PageFault { range, state, .. } => {
                 map.insert(range.clone(), *state);
             }
             TraceePayload::Unmap { range } => {
@@ -202,7 +574,19 @@ impl TraceePayload {
                     }
                 }
             }
-            TraceePayload::CmdLineChange { .. } => {
+            TraceePayload::Mapped { .. }
+            | TraceePayload::Protect { .. }
+            | TraceePayload::CmdLineChange { .. }
+            | TraceePayload::Degraded { .. }
+            | TraceePayload::Warning { .. }
+            | TraceePayload::Drift { .. }
+            | TraceePayload::Forked { .. }
+            | TraceePayload::Usage(_)
+            | TraceePayload::ZeroFilled(_)
+            | TraceePayload::Duplicates(_)
+            | TraceePayload::NumaNodes(_)
+            | TraceePayload::WorkingSet(_)
+            | TraceePayload::SetAttribute { .. } => {
                 // do nothing
             }
             TraceePayload::Exit { .. } => {
@@ -210,4 +594,290 @@ impl TraceePayload {
             }
         }
     }
+
+    pub fn apply_to_touches(&self, touches: &mut Touches) {
+        match self {
+            TraceePayload::Exec => touches.clear(),
+            TraceePayload::PageFault { range, thread, .. } => match thread {
+                Some(thread) => touches.insert(range.clone(), *thread),
+                None => touches.remove(range.clone()),
+            },
+            TraceePayload::MemStateChange { range, state } => {
+                // swapped out pages are still the same pages, but freed ones
+                // will be touched afresh
+                if !state.is_resident() && *state != MemState::Swapped {
+                    touches.remove(range.clone());
+                }
+            }
+            TraceePayload::Unmap { range } => touches.remove(range.clone()),
+            TraceePayload::Remap {
+                old_range,
+                new_range,
+            } => move_ranges(touches, old_range, new_range),
+            TraceePayload::Mapped { .. }
+            | TraceePayload::Protect { .. }
+            | TraceePayload::CmdLineChange { .. }
+            | TraceePayload::Degraded { .. }
+            | TraceePayload::Warning { .. }
+            | TraceePayload::Drift { .. }
+            | TraceePayload::Forked { .. }
+            | TraceePayload::Usage(_)
+            | TraceePayload::ZeroFilled(_)
+            | TraceePayload::Duplicates(_)
+            | TraceePayload::NumaNodes(_)
+            | TraceePayload::WorkingSet(_)
+            | TraceePayload::SetAttribute { .. }
+            | TraceePayload::Exit => {}
+        }
+    }
+
+    pub fn apply_to_attrs(&self, attrs: &mut AttrMap) {
+        match self {
+            TraceePayload::Exec => attrs.clear(),
+            TraceePayload::SetAttribute { range, attr } => {
+                // every part of the range, including the ones with default
+                // attributes, which aren't in the map
+                let mut parts = vec![];
+                let mut pos = range.start;
+                for (existing, existing_attrs) in attrs.overlapping(range) {
+                    let existing = existing.start.max(range.start)..existing.end.min(range.end);
+                    if pos < existing.start {
+                        parts.push((pos..existing.start, Attributes::default()));
+                    }
+                    pos = existing.end;
+                    parts.push((existing, *existing_attrs));
+                }
+                if pos < range.end {
+                    parts.push((pos..range.end, Attributes::default()));
+                }
+
+                for (part, mut part_attrs) in parts {
+                    part_attrs.set(*attr);
+                    if part_attrs == Attributes::default() {
+                        attrs.remove(part);
+                    } else {
+                        attrs.insert(part, part_attrs);
+                    }
+                }
+            }
+            TraceePayload::Unmap { range } => attrs.remove(range.clone()),
+            TraceePayload::Remap {
+                old_range,
+                new_range,
+            } => move_ranges(attrs, old_range, new_range),
+            TraceePayload::Mapped { .. }
+            | TraceePayload::Protect { .. }
+            | TraceePayload::MemStateChange { .. }
+            | TraceePayload::PageFault { .. }
+            | TraceePayload::CmdLineChange { .. }
+            | TraceePayload::Degraded { .. }
+            | TraceePayload::Warning { .. }
+            | TraceePayload::Drift { .. }
+            | TraceePayload::Forked { .. }
+            | TraceePayload::Usage(_)
+            | TraceePayload::ZeroFilled(_)
+            | TraceePayload::Duplicates(_)
+            | TraceePayload::NumaNodes(_)
+            | TraceePayload::WorkingSet(_)
+            | TraceePayload::Exit => {}
+        }
+    }
+
+    pub fn apply_to_mappings(&self, mappings: &mut MappingMap) {
+        match self {
+            TraceePayload::Exec => mappings.clear(),
+            TraceePayload::Mapped { range, mapping } => {
+                mappings.insert(range.clone(), mapping.clone());
+            }
+            TraceePayload::Protect { range, prot } => {
+                let changed: Vec<_> = mappings
+                    .overlapping(range)
+                    .map(|(existing, mapping)| {
+                        let existing = existing.start.max(range.start)..existing.end.min(range.end);
+                        let mapping = Mapping {
+                            prot: *prot,
+                            ..mapping.clone()
+                        };
+                        (existing, mapping)
+                    })
+                    .collect();
+                for (range, mapping) in changed {
+                    mappings.insert(range, mapping);
+                }
+            }
+            TraceePayload::Unmap { range } => mappings.remove(range.clone()),
+            TraceePayload::Remap {
+                old_range,
+                new_range,
+            } => {
+                // mremap works on a single mapping, and what it grew into is
+                // part of it too
+                let Some(mapping) = mappings.get(&old_range.start).cloned() else {
+                    return;
+                };
+                mappings.remove(old_range.clone());
+                mappings.insert(new_range.clone(), mapping);
+            }
+            TraceePayload::MemStateChange { .. }
+            | TraceePayload::PageFault { .. }
+            | TraceePayload::CmdLineChange { .. }
+            | TraceePayload::Degraded { .. }
+            | TraceePayload::Warning { .. }
+            | TraceePayload::Drift { .. }
+            | TraceePayload::Forked { .. }
+            | TraceePayload::Usage(_)
+            | TraceePayload::ZeroFilled(_)
+            | TraceePayload::Duplicates(_)
+            | TraceePayload::NumaNodes(_)
+            | TraceePayload::WorkingSet(_)
+            | TraceePayload::SetAttribute { .. }
+            | TraceePayload::Exit => {}
+        }
+    }
+}
+
+/// Moves what's in `old_range` to `new_range`, dropping what doesn't fit if
+/// it shrunk.
+fn move_ranges<V: Clone + Eq>(
+    map: &mut RangeMap<u64, V>,
+    old_range: &Range<u64>,
+    new_range: &Range<u64>,
+) {
+    let moved: Vec<_> = map
+        .overlapping(old_range)
+        .map(|(range, value)| {
+            (
+                range.start.max(old_range.start)..range.end.min(old_range.end),
+                value.clone(),
+            )
+        })
+        .collect();
+    map.remove(old_range.clone());
+    for (range, value) in moved {
+        let start = range.start - old_range.start + new_range.start;
+        let end = (range.end - old_range.start + new_range.start).min(new_range.end);
+        if start < end {
+            map.insert(start..end, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forked_memmap_shares_resident_pages() {
+        let mut parent = MemMap::new();
+        parent.insert(0x1000..0x3000, MemState::Resident);
+        parent.insert(0x3000..0x4000, MemState::NotResident);
+        parent.insert(0x4000..0x6000, MemState::HugeResident);
+        parent.insert(0x6000..0x7000, MemState::Prefaulted);
+        parent.insert(0x7000..0x8000, MemState::Swapped);
+
+        let child: Vec<_> = forked_memmap(&parent).into_iter().collect();
+        assert_eq!(
+            child,
+            vec![
+                (0x1000..0x3000, MemState::SharedCow),
+                (0x3000..0x4000, MemState::NotResident),
+                (0x4000..0x6000, MemState::SharedCow),
+                (0x6000..0x7000, MemState::Prefaulted),
+                (0x7000..0x8000, MemState::Swapped),
+            ]
+        );
+    }
+
+    fn set(attrs: &mut AttrMap, range: Range<u64>, attr: Attribute) {
+        TraceePayload::SetAttribute { range, attr }.apply_to_attrs(attrs);
+    }
+
+    fn ranges(attrs: &AttrMap) -> Vec<Range<u64>> {
+        attrs.iter().map(|(range, _)| range.clone()).collect()
+    }
+
+    #[test]
+    fn default_attributes_are_removed() {
+        let mut attrs = AttrMap::new();
+        set(
+            &mut attrs,
+            0x1000..0x4000,
+            Attribute::HugePages(HugePageAdvice::Huge),
+        );
+        set(
+            &mut attrs,
+            0x2000..0x3000,
+            Attribute::HugePages(HugePageAdvice::None),
+        );
+        assert_eq!(ranges(&attrs), vec![0x1000..0x2000, 0x3000..0x4000]);
+
+        // clearing advice where there was none doesn't add anything
+        set(
+            &mut attrs,
+            0x5000..0x6000,
+            Attribute::HugePages(HugePageAdvice::None),
+        );
+        assert_eq!(ranges(&attrs), vec![0x1000..0x2000, 0x3000..0x4000]);
+    }
+
+    #[test]
+    fn unmap_and_remap_move_attributes() {
+        let mut attrs = AttrMap::new();
+        set(
+            &mut attrs,
+            0x1000..0x3000,
+            Attribute::HugePages(HugePageAdvice::NoHuge),
+        );
+        TraceePayload::Remap {
+            old_range: 0x1000..0x3000,
+            new_range: 0x8000..0xa000,
+        }
+        .apply_to_attrs(&mut attrs);
+        assert_eq!(ranges(&attrs), vec![0x8000..0xa000]);
+
+        TraceePayload::Unmap {
+            range: 0x8000..0x9000,
+        }
+        .apply_to_attrs(&mut attrs);
+        assert_eq!(ranges(&attrs), vec![0x9000..0xa000]);
+    }
+
+    #[test]
+    fn attributes_combine_over_partial_overlaps() {
+        let mut attrs = AttrMap::new();
+        set(&mut attrs, 0x2000..0x4000, Attribute::Locked(true));
+        set(
+            &mut attrs,
+            0x1000..0x3000,
+            Attribute::HugePages(HugePageAdvice::Huge),
+        );
+
+        let locked = Attributes {
+            locked: true,
+            ..Default::default()
+        };
+        let huge = Attributes {
+            huge_pages: HugePageAdvice::Huge,
+            ..Default::default()
+        };
+        let both = Attributes {
+            locked: true,
+            huge_pages: HugePageAdvice::Huge,
+        };
+        assert_eq!(
+            attrs
+                .iter()
+                .map(|(r, a)| (r.clone(), *a))
+                .collect::<Vec<_>>(),
+            vec![
+                (0x1000..0x2000, huge),
+                (0x2000..0x3000, both),
+                (0x3000..0x4000, locked),
+            ]
+        );
+
+        // unlocking keeps the advice
+        set(&mut attrs, 0x1000..0x4000, Attribute::Locked(false));
+        assert_eq!(ranges(&attrs), vec![0x1000..0x3000]);
+    }
 }
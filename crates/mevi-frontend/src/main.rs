@@ -1,17 +1,41 @@
 use std::{collections::HashMap, ops::Range};
 
-use futures_util::StreamExt;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use gloo_net::websocket::{futures::WebSocket, Message};
 use humansize::{make_format, BINARY};
 use itertools::Itertools;
-use mevi_common::{MemMap, MemState, MeviEvent, TraceeId, TraceePayload};
+use mevi_common::{
+    forked_memmap, mapping_at, AttrMap, Attributes, Duplicates, HugePageAdvice, InspectRequest,
+    Inspection, Mapping, MappingBacking, MappingMap, MappingOrigin, MemMap, MemState, MeviEvent,
+    NumaMap, OutputChunk, OutputStream, Touches, TraceeId, TraceePayload, Usage, WorkingSet,
+    ZeroFilled, MAX_WARNINGS,
+};
+use rangemap::RangeSet;
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
 
 struct Group {
     start: u64,
     size: u64,
-    ranges: Vec<(Range<u64>, MemState)>,
+    spans: Vec<Span>,
+}
+
+/// A range of memory, drawn as one cell
+#[derive(Clone)]
+struct Span {
+    range: Range<u64>,
+    state: MemState,
+    /// The thread that first touched it, if we're coloring by thread
+    thread: Option<TraceeId>,
+    /// Resident, but outside the working set
+    cold: bool,
+    /// Resident, and all zeros when we last looked
+    zero_filled: bool,
+    /// Has the same contents as another page when we last looked
+    duplicate: bool,
+    /// The NUMA node it's on, if we're coloring by node
+    node: Option<u32>,
+    attrs: Attributes,
 }
 
 #[derive(Clone)]
@@ -19,6 +43,19 @@ struct TraceeState {
     tid: TraceeId,
     map: MemMap,
     cmdline: Vec<String>,
+    degraded: Option<String>,
+    warnings: Vec<String>,
+    drift: Option<i64>,
+    touches: Touches,
+    touched_bytes: HashMap<TraceeId, u64>,
+    working_set: Option<WorkingSet>,
+    attrs: AttrMap,
+    parent: Option<TraceeId>,
+    usage: Option<Usage>,
+    zero_filled: Option<ZeroFilled>,
+    duplicates: Option<Duplicates>,
+    numa_nodes: Option<NumaMap>,
+    mappings: MappingMap,
 }
 
 impl TraceeState {
@@ -26,7 +63,7 @@ impl TraceeState {
         self.map
             .iter()
             .map(|(range, state)| {
-                if state == &MemState::Resident {
+                if state.is_resident() {
                     range.end - range.start
                 } else {
                     0
@@ -34,6 +71,341 @@ impl TraceeState {
             })
             .sum()
     }
+
+    /// The memory map, split wherever which thread first touched it (if
+    /// `by_thread`), which NUMA node it's on (if `by_node`), whether it's in
+    /// the working set, all zeros or duplicated, or its attributes change.
+    fn spans(&self, by_thread: bool, by_node: bool) -> Vec<Span> {
+        let mut spans: Vec<Span> = self
+            .map
+            .iter()
+            .map(|(range, state)| Span {
+                range: range.clone(),
+                state: *state,
+                thread: None,
+                // without a working set, nothing is cold
+                cold: self.working_set.is_some() && state.is_resident(),
+                zero_filled: false,
+                duplicate: false,
+                node: None,
+                attrs: Default::default(),
+            })
+            .collect();
+
+        if by_thread {
+            spans = split_spans(
+                spans,
+                |range| {
+                    self.touches
+                        .overlapping(range)
+                        .map(|(touched, thread)| (touched.clone(), *thread))
+                        .collect()
+                },
+                |span, thread| {
+                    if span.state.is_resident() {
+                        span.thread = Some(thread);
+                    }
+                },
+            );
+        }
+        if let (true, Some(nodes)) = (by_node, &self.numa_nodes) {
+            spans = split_spans(
+                spans,
+                |range| {
+                    nodes
+                        .overlapping(range)
+                        .map(|(located, node)| (located.clone(), *node))
+                        .collect()
+                },
+                |span, node| {
+                    if span.state.is_resident() {
+                        span.node = Some(node);
+                    }
+                },
+            );
+        }
+        if let Some(working_set) = &self.working_set {
+            spans = split_spans(
+                spans,
+                |range| {
+                    working_set
+                        .written
+                        .overlapping(range)
+                        .map(|written| (written.clone(), ()))
+                        .collect()
+                },
+                |span, ()| span.cold = false,
+            );
+        }
+        if let Some(zero_filled) = &self.zero_filled {
+            spans = split_spans(
+                spans,
+                |range| {
+                    zero_filled
+                        .pages
+                        .overlapping(range)
+                        .map(|zeros| (zeros.clone(), ()))
+                        .collect()
+                },
+                // it may have been written to or freed since the last scan
+                |span, ()| span.zero_filled = span.state.is_resident(),
+            );
+        }
+        if let Some(duplicates) = &self.duplicates {
+            spans = split_spans(
+                spans,
+                |range| {
+                    duplicates
+                        .pages
+                        .overlapping(range)
+                        .map(|dupes| (dupes.clone(), ()))
+                        .collect()
+                },
+                |span, ()| span.duplicate = span.state.is_resident(),
+            );
+        }
+        split_spans(
+            spans,
+            |range| {
+                self.attrs
+                    .overlapping(range)
+                    .map(|(range, attrs)| (range.clone(), *attrs))
+                    .collect()
+            },
+            |span, attrs| span.attrs = attrs,
+        )
+    }
+
+    /// Resident bytes that are locked, like `VmLck` in `/proc/PID/status`
+    fn locked_bytes(&self) -> u64 {
+        let mut locked = 0;
+        for (attr_range, attrs) in self.attrs.iter() {
+            if !attrs.locked {
+                continue;
+            }
+            for (range, state) in self.map.overlapping(attr_range) {
+                if state.is_resident() {
+                    locked += range.end.min(attr_range.end) - range.start.max(attr_range.start);
+                }
+            }
+        }
+        locked
+    }
+
+    /// Resident bytes on each NUMA node, in node order
+    fn node_bytes(&self) -> Vec<(u32, u64)> {
+        let mut per_node: HashMap<u32, u64> = Default::default();
+        for (located, node) in self.numa_nodes.iter().flat_map(|nodes| nodes.iter()) {
+            for (range, state) in self.map.overlapping(located) {
+                if state.is_resident() {
+                    *per_node.entry(*node).or_default() +=
+                        range.end.min(located.end) - range.start.max(located.start);
+                }
+            }
+        }
+        per_node.into_iter().sorted().collect()
+    }
+
+    /// Resident bytes among `pages`
+    fn resident_bytes(&self, pages: &RangeSet<u64>) -> u64 {
+        let mut resident = 0;
+        for pages in pages.iter() {
+            for (range, state) in self.map.overlapping(pages) {
+                if state.is_resident() {
+                    resident += range.end.min(pages.end) - range.start.max(pages.start);
+                }
+            }
+        }
+        resident
+    }
+
+    /// Resident bytes that were written to within the working set window
+    fn hot_bytes(&self) -> Option<u64> {
+        let working_set = self.working_set.as_ref()?;
+        let mut hot = 0;
+        for written in working_set.written.iter() {
+            for (range, state) in self.map.overlapping(written) {
+                if state.is_resident() {
+                    hot += range.end.min(written.end) - range.start.max(written.start);
+                }
+            }
+        }
+        Some(hot)
+    }
+}
+
+/// Splits `spans` wherever they start or stop overlapping the ranges
+/// `overlay` finds for them, and calls `apply` on the parts that do.
+fn split_spans<V: Copy>(
+    spans: Vec<Span>,
+    overlay: impl Fn(&Range<u64>) -> Vec<(Range<u64>, V)>,
+    apply: impl Fn(&mut Span, V),
+) -> Vec<Span> {
+    let mut split = vec![];
+    for span in spans {
+        let range = span.range.clone();
+        let mut pos = range.start;
+        for (overlapping, value) in overlay(&range) {
+            let overlapping = overlapping.start.max(range.start)..overlapping.end.min(range.end);
+            if pos < overlapping.start {
+                split.push(Span {
+                    range: pos..overlapping.start,
+                    ..span.clone()
+                });
+            }
+            pos = overlapping.end;
+            let mut part = Span {
+                range: overlapping,
+                ..span.clone()
+            };
+            apply(&mut part, value);
+            split.push(part);
+        }
+        if pos < range.end {
+            split.push(Span {
+                range: pos..range.end,
+                ..span
+            });
+        }
+    }
+    split
+}
+
+/// Which mappings the all-zero pages are in, biggest first
+fn zero_filled_title(zero_filled: &ZeroFilled) -> String {
+    let formatter = make_format(BINARY);
+    let mut title = "Resident, but all zeros".to_string();
+    for mapping in zero_filled
+        .per_mapping
+        .iter()
+        .sorted_by_key(|m| std::cmp::Reverse(m.bytes))
+    {
+        title.push_str(&format!(
+            "\n{} in {} at {:x?}",
+            formatter(mapping.bytes),
+            mapping.name,
+            mapping.range
+        ));
+    }
+    title
+}
+
+/// Which mappings were written to recently, biggest first
+fn working_set_title(working_set: &WorkingSet) -> String {
+    let formatter = make_format(BINARY);
+    let mut title = format!(
+        "Resident and written to in the last {}s",
+        working_set.window_secs
+    );
+    for mapping in working_set
+        .per_mapping
+        .iter()
+        .sorted_by_key(|m| std::cmp::Reverse(m.bytes))
+    {
+        title.push_str(&format!(
+            "\n{} in {} at {:x?}",
+            formatter(mapping.bytes),
+            mapping.name,
+            mapping.range
+        ));
+    }
+    title
+}
+
+/// Permissions, backing and origin, e.g. `rw- anonymous mapping (mmap,
+/// MAP_PRIVATE|MAP_ANONYMOUS)`
+fn describe_mapping(mapping: &Mapping) -> String {
+    let backing = match &mapping.backing {
+        MappingBacking::Anon => "anonymous mapping".to_string(),
+        MappingBacking::Heap => "heap".to_string(),
+        MappingBacking::Stack => "stack".to_string(),
+        MappingBacking::File(path) => format!("private mapping of {path}"),
+        MappingBacking::Shm(Some(path)) => format!("shared memory {path}"),
+        MappingBacking::Shm(None) => "shared anonymous mapping".to_string(),
+    };
+    let origin = match mapping.origin {
+        MappingOrigin::Mmap => "mmap",
+        MappingOrigin::Brk => "brk",
+        MappingOrigin::Preexisting => "there before mevi attached",
+    };
+    let mut desc = format!("{} {backing} ({origin}", mapping.prot);
+    if !mapping.flags.is_empty() {
+        desc.push_str(", ");
+        desc.push_str(&mapping.flags.join("|"));
+    }
+    desc.push(')');
+    if mapping.unplaced_huge_bytes > 0 {
+        desc.push_str(&format!(
+            ", with {} in transparent huge pages when mevi attached, at unknown addresses",
+            make_format(BINARY)(mapping.unplaced_huge_bytes)
+        ));
+    }
+    desc
+}
+
+/// How much merging identical pages would save, and where they are
+fn duplicates_title(duplicates: &Duplicates) -> String {
+    let formatter = make_format(BINARY);
+    let mut title = format!(
+        "Resident, with the same contents as other pages: merging them would free {}\n{} within this process",
+        formatter(duplicates.mergeable),
+        formatter(duplicates.within)
+    );
+    for (other, bytes) in &duplicates.with {
+        title.push_str(&format!("\n{} with {}", formatter(*bytes), other.0));
+    }
+    for mapping in duplicates
+        .per_mapping
+        .iter()
+        .sorted_by_key(|m| std::cmp::Reverse(m.bytes))
+    {
+        title.push_str(&format!(
+            "\n{} in {} at {:x?}",
+            formatter(mapping.bytes),
+            mapping.name,
+            mapping.range
+        ));
+    }
+    title
+}
+
+/// A color for each thread, spread around the color wheel
+fn thread_color(thread: TraceeId) -> String {
+    format!("hsl({}, 65%, 45%)", thread.0.wrapping_mul(137) % 360)
+}
+
+/// A color for each NUMA node, few enough that they should stay far apart
+fn node_color(node: u32) -> String {
+    format!("hsl({}, 55%, 40%)", (200 + node.wrapping_mul(97)) % 360)
+}
+
+/// How many lines of tracee output the console panel keeps
+const CONSOLE_MAX_LINES: usize = 1000;
+
+#[derive(Clone, Default)]
+struct Console {
+    lines: Vec<ConsoleLine>,
+    /// The start of a UTF-8 sequence that got cut between two chunks, per
+    /// stream
+    pending: HashMap<OutputStream, Vec<u8>>,
+}
+
+impl Console {
+    fn clear(&mut self) {
+        self.lines.clear();
+        self.pending.clear();
+    }
+}
+
+#[derive(Clone)]
+struct ConsoleLine {
+    stream: OutputStream,
+    elapsed_ms: u64,
+    /// total resident set of all tracees when the line started printing, so
+    /// output can be correlated with memory usage
+    rss: u64,
+    text: String,
+    complete: bool,
 }
 
 async fn connect_to_ws() -> WebSocket {
@@ -72,10 +444,56 @@ async fn connect_to_ws() -> WebSocket {
     ws
 }
 
+/// What the top bar adds up over all processes
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Metric {
+    /// The memory mevi tracks
+    Tracked,
+    Rss,
+    Pss,
+    Uss,
+}
+
+impl Metric {
+    const ALL: [Metric; 4] = [Metric::Tracked, Metric::Rss, Metric::Pss, Metric::Uss];
+
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Tracked => "Resident set",
+            Metric::Rss => "RSS",
+            Metric::Pss => "PSS",
+            Metric::Uss => "USS",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Metric::Tracked => "Resident memory that mevi tracks",
+            Metric::Rss => "Every resident page each process maps, as the kernel counts it: shared pages count once per process",
+            Metric::Pss => "Shared pages count for their share in each process, so nothing is counted twice",
+            Metric::Uss => "Only the pages no other process maps",
+        }
+    }
+
+    /// How much of it `tracee` uses, if we know
+    fn of(self, tracee: &TraceeState) -> Option<u64> {
+        match self {
+            Metric::Tracked => Some(tracee.total_rss()),
+            Metric::Rss => tracee.usage.map(|u| u.rss),
+            Metric::Pss => tracee.usage.map(|u| u.pss),
+            Metric::Uss => tracee.usage.map(|u| u.uss),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Options {
     show_gaps: bool,
     show_nonresident_groups: bool,
+    show_console: bool,
+    color_by_thread: bool,
+    color_by_numa_node: bool,
+    metric: Metric,
 }
 
 impl Default for Options {
@@ -83,6 +501,10 @@ impl Default for Options {
         Self {
             show_gaps: true,
             show_nonresident_groups: true,
+            show_console: true,
+            color_by_thread: false,
+            color_by_numa_node: false,
+            metric: Metric::Tracked,
         }
     }
 }
@@ -101,6 +523,34 @@ impl Options {
             ..*self
         }
     }
+
+    fn toggle_show_console(&self) -> Self {
+        Self {
+            show_console: !self.show_console,
+            ..*self
+        }
+    }
+
+    fn toggle_color_by_thread(&self) -> Self {
+        Self {
+            color_by_thread: !self.color_by_thread,
+            // cells only have one color
+            color_by_numa_node: false,
+            ..*self
+        }
+    }
+
+    fn toggle_color_by_numa_node(&self) -> Self {
+        Self {
+            color_by_numa_node: !self.color_by_numa_node,
+            color_by_thread: false,
+            ..*self
+        }
+    }
+
+    fn with_metric(&self, metric: Metric) -> Self {
+        Self { metric, ..*self }
+    }
 }
 
 #[function_component(App)]
@@ -108,18 +558,26 @@ fn app() -> Html {
     let options = use_state(Options::default);
     let live = use_state(|| false);
     let tracees = use_state(|| -> HashMap<TraceeId, TraceeState> { Default::default() });
+    let console = use_state(Console::default);
+    let inspection = use_state(|| None::<Inspection>);
+    let ws_write = use_mut_ref(|| None::<SplitSink<WebSocket, Message>>);
 
     {
         let tracees = tracees.clone();
+        let console = console.clone();
         let live = live.clone();
+        let inspection = inspection.clone();
+        let ws_write = ws_write.clone();
         use_effect_with_deps(
             move |_| {
                 let mut tracees_acc = HashMap::new();
+                let mut console_acc = Console::default();
 
                 spawn_local(async move {
                     let mut batch_size = 0;
 
-                    let (_, mut read) = connect_to_ws().await.split();
+                    let (write, mut read) = connect_to_ws().await.split();
+                    *ws_write.borrow_mut() = Some(write);
                     live.set(true);
 
                     while let Some(msg) = read.next().await {
@@ -130,9 +588,13 @@ fn app() -> Html {
                                 live.set(false);
 
                                 gloo_console::log!("Reconnecting...");
-                                (_, read) = connect_to_ws().await.split();
+                                let write;
+                                (write, read) = connect_to_ws().await.split();
+                                *ws_write.borrow_mut() = Some(write);
                                 tracees_acc.clear();
                                 tracees.set(tracees_acc.clone());
+                                console_acc.clear();
+                                console.set(console_acc.clone());
                                 live.set(true);
                                 continue;
                             }
@@ -149,10 +611,14 @@ fn app() -> Html {
 
                                 for ev in evs {
                                     // gloo_console::log!(format!("{:?}", ev));
-                                    apply_ev(&mut tracees_acc, ev);
+                                    match ev {
+                                        MeviEvent::Inspection(i) => inspection.set(Some(i)),
+                                        ev => apply_ev(&mut tracees_acc, &mut console_acc, ev),
+                                    }
                                 }
 
                                 tracees.set(tracees_acc.clone());
+                                console.set(console_acc.clone());
                                 // gloo_console::log!(format!("flushing {} events", batch_size));
                                 batch_size = 0;
                             }
@@ -166,22 +632,49 @@ fn app() -> Html {
     }
 
     let mut total_virt: u64 = 0;
-    let mut total_res: u64 = 0;
-    for (range, mem_state) in tracees.values().flat_map(|v| v.map.iter()) {
+    for (range, _) in tracees.values().flat_map(|v| v.map.iter()) {
         total_virt += range.end - range.start;
-
-        if *mem_state == MemState::Resident {
-            total_res += range.end - range.start;
-        }
     }
+    let total_res: u64 = tracees
+        .values()
+        .filter_map(|tracee| options.metric.of(tracee))
+        .sum();
+
+    let inspect = {
+        let ws_write = ws_write.clone();
+        Callback::from(move |req: InspectRequest| {
+            let ws_write = ws_write.clone();
+            spawn_local(async move {
+                // if it's being sent something else, this request is dropped
+                let Some(mut write) = ws_write.borrow_mut().take() else {
+                    return;
+                };
+                let data = mevi_common::serialize_request(&req).unwrap();
+                if let Err(e) = write.send(Message::Bytes(data)).await {
+                    gloo_console::log!("Couldn't send inspect request:", e.to_string());
+                }
+                // unless we reconnected in the meantime
+                ws_write.borrow_mut().get_or_insert(write);
+            });
+        })
+    };
+
+    let total_mergeable: Option<u64> = tracees
+        .values()
+        .filter_map(|tracee| tracee.duplicates.as_ref())
+        .map(|duplicates| duplicates.mergeable)
+        .reduce(|a, b| a + b);
 
     let formatter = make_format(BINARY);
     html! {
         <>
             <div class="top-bar">
                 <span class="brand"><span>{"me"}</span><span class="brand-rest">{"vi"}</span></span>
-                <span class="mem-stats rss"><span class="mem-square"></span><span class="name">{"Resident set"}</span>{format!("{}", formatter(total_res))}</span>
+                <span class="mem-stats rss"><span class="mem-square"></span><span class="name" title={options.metric.description()}>{options.metric.name()}</span>{format!("{}", formatter(total_res))}</span>
                 <span class="mem-stats virt"><span class="mem-square"></span><span class="name">{"Virtual set"}</span>{format!("{}", formatter(total_virt))}</span>
+                if let Some(mergeable) = total_mergeable {
+                    <span class="mem-stats duplicate" title="What merging identical pages across all traced processes would free, like KSM does"><span class="mem-square"></span><span class="name">{"Mergeable"}</span>{formatter(mergeable).to_string()}</span>
+                }
                 <span class={ if *live { "live-indicator live" } else { "live-indicator offline" } }>{ if *live { "LIVE" } else { "OFFLINE" } }</span>
 
                 <span class="option">
@@ -196,7 +689,122 @@ fn app() -> Html {
                         {"Show non-resident groups"}
                     </label>
                 </span>
+                <span class="option">
+                    <label>
+                        <input type="checkbox" checked={options.show_console} onclick={{ let options = options.clone();  move |_| options.set(options.toggle_show_console()) }} />
+                        {"Show console"}
+                    </label>
+                </span>
+                <span class="option">
+                    <label>
+                        <input type="checkbox" checked={options.color_by_thread} onclick={{ let options = options.clone();  move |_| options.set(options.toggle_color_by_thread()) }} />
+                        {"Color by thread"}
+                    </label>
+                </span>
+                <span class="option">
+                    <label title="Needs --numa">
+                        <input type="checkbox" checked={options.color_by_numa_node} onclick={{ let options = options.clone();  move |_| options.set(options.toggle_color_by_numa_node()) }} />
+                        {"Color by NUMA node"}
+                    </label>
+                </span>
+                <span class="option">
+                    {
+                        Metric::ALL.iter().map(|metric| {
+                            let metric = *metric;
+                            let onclick = { let options = options.clone(); move |_| options.set(options.with_metric(metric)) };
+                            html! {
+                                <label title={metric.description()}>
+                                    <input type="radio" name="metric" checked={options.metric == metric} {onclick} />
+                                    {metric.name()}
+                                </label>
+                            }
+                        }).collect::<Html>()
+                    }
+                </span>
             </div>
+            {{
+                if options.show_console && !console.lines.is_empty() {
+                    let mut prev_rss = None;
+                    html! {
+                        <div class="console">
+                            {
+                                console.lines.iter().map(|line| {
+                                    let delta = match prev_rss {
+                                        Some(prev) if line.rss > prev => format!("+{}", formatter(line.rss - prev)),
+                                        Some(prev) if line.rss < prev => format!("-{}", formatter(prev - line.rss)),
+                                        _ => String::new(),
+                                    };
+                                    prev_rss = Some(line.rss);
+                                    let stream_class = match line.stream {
+                                        OutputStream::Stdout | OutputStream::Pty => "console-line",
+                                        OutputStream::Stderr => "console-line stderr",
+                                    };
+                                    html! {
+                                        <div class={stream_class}>
+                                            <span class="console-time">{format!("{:>8.3}s", line.elapsed_ms as f64 / 1000.0)}</span>
+                                            <span class="console-rss" title="Resident set when this line was printed">{formatter(line.rss).to_string()}</span>
+                                            <span class="console-delta">{delta}</span>
+                                            <span class="console-text">{&line.text}</span>
+                                        </div>
+                                    }
+                                }).collect::<Html>()
+                            }
+                        </div>
+                    }
+                } else {
+                    html! {}
+                }
+            }}
+            {{
+                match &*inspection {
+                    Some(inspected) => {
+                        let nav = |addr: u64| {
+                            let inspect = inspect.clone();
+                            let tid = inspected.tid;
+                            move |_| inspect.emit(InspectRequest { tid, addr })
+                        };
+                        let close = { let inspection = inspection.clone(); move |_| inspection.set(None) };
+                        html! {
+                            <div class="inspector">
+                                <div class="inspector-header">
+                                    <span>{format!("PID {}, page at {:x}", inspected.tid.0, inspected.addr)}</span>
+                                    <button onclick={nav(inspected.addr.saturating_sub(inspected.page_size))}>{"previous page"}</button>
+                                    <button onclick={nav(inspected.addr + inspected.page_size)}>{"next page"}</button>
+                                    <button onclick={close}>{"close"}</button>
+                                </div>
+                                {
+                                    match &inspected.result {
+                                        Ok(contents) => html! {
+                                            <>
+                                                <div class="inspector-guesses">
+                                                    if contents.zeros {
+                                                        <div>{"All zeros"}</div>
+                                                    }
+                                                    {
+                                                        contents.pointers.iter().map(|p| html! {
+                                                            <div>{format!("+{:x}: pointer to {:x} in {}", p.offset, p.value, p.mapping)}</div>
+                                                        }).collect::<Html>()
+                                                    }
+                                                    {
+                                                        contents.strings.iter().map(|(offset, s)| html! {
+                                                            <div>{format!("+{offset:x}: string {s:?}")}</div>
+                                                        }).collect::<Html>()
+                                                    }
+                                                </div>
+                                                <pre class="inspector-hexdump">{&contents.hexdump}</pre>
+                                            </>
+                                        },
+                                        Err(e) => html! {
+                                            <div class="inspector-error">{format!("Couldn't read it: {e}")}</div>
+                                        },
+                                    }
+                                }
+                            </div>
+                        }
+                    }
+                    None => html! {},
+                }
+            }}
             {{
                 tracees.values().sorted_by_key(|p| std::cmp::Reverse(p.total_rss())).map(|tracee| {
                     html! {
@@ -208,20 +816,125 @@ fn app() -> Html {
                                         // collect virt/rss stats for process
                                         let mut virt: u64 = 0;
                                         let mut res: u64 = 0;
+                                        let mut huge: u64 = 0;
+                                        let mut swapped: u64 = 0;
+                                        let mut lazy: u64 = 0;
+                                        let mut shared_cow: u64 = 0;
                                         for (range, mem_state) in tracee.map.iter() {
                                                 virt += range.end - range.start;
 
-                                            if *mem_state == MemState::Resident {
+                                            if mem_state.is_resident() {
                                                 res += range.end - range.start;
                                             }
+                                            match mem_state {
+                                                MemState::HugeResident => huge += range.end - range.start,
+                                                MemState::Swapped => swapped += range.end - range.start,
+                                                MemState::LazyFree => lazy += range.end - range.start,
+                                                MemState::SharedCow => shared_cow += range.end - range.start,
+                                                _ => {}
+                                            }
                                         }
                                         html! {
                                             <>
                                                 <span class="mem-stats rss"><span class="mem-square"></span><span>{format!("{}", formatter(res))}</span></span>
+                                                if huge > 0 {
+                                                    <span class="mem-stats huge" title="Resident in huge pages"><span class="mem-square"></span><span>{formatter(huge).to_string()}</span></span>
+                                                }
+                                                if swapped > 0 {
+                                                    <span class="mem-stats swapped" title="Swapped out"><span class="mem-square"></span><span>{formatter(swapped).to_string()}</span></span>
+                                                }
+                                                {{
+                                                    let locked = tracee.locked_bytes();
+                                                    if locked > 0 {
+                                                        html! {
+                                                            <span class="mem-stats locked" title="Resident and locked with mlock"><span class="mem-square"></span><span>{formatter(locked).to_string()}</span></span>
+                                                        }
+                                                    } else {
+                                                        html! {}
+                                                    }
+                                                }}
+                                                if shared_cow > 0 {
+                                                    <span class="mem-stats cow" title="Shared copy-on-write with a parent or child process"><span class="mem-square"></span><span>{formatter(shared_cow).to_string()}</span></span>
+                                                }
+                                                if lazy > 0 {
+                                                    <span class="mem-stats lazy" title="Freed with MADV_FREE, resident until reclaimed"><span class="mem-square"></span><span>{formatter(lazy).to_string()}</span></span>
+                                                }
+                                                if let Some(zero_filled) = &tracee.zero_filled {
+                                                    <span class="mem-stats zero-filled" title={zero_filled_title(zero_filled)}><span class="mem-square"></span><span>{formatter(tracee.resident_bytes(&zero_filled.pages)).to_string()}</span></span>
+                                                }
+                                                if let Some(duplicates) = &tracee.duplicates {
+                                                    <span class="mem-stats duplicate" title={duplicates_title(duplicates)}><span class="mem-square"></span><span>{formatter(tracee.resident_bytes(&duplicates.pages)).to_string()}</span></span>
+                                                }
+                                                if let (Some(hot), Some(working_set)) = (tracee.hot_bytes(), &tracee.working_set) {
+                                                    <span class="mem-stats hot" title={working_set_title(working_set)}><span class="mem-square"></span><span>{formatter(hot).to_string()}</span></span>
+                                                }
                                                 <span class="mem-stats virt"><span class="mem-square"></span><span>{format!("{}", formatter(virt))}</span></span>
                                             </>
                                         }
                                     }}
+                                    {
+                                        match &tracee.degraded {
+                                            Some(reason) => html! {
+                                                <span class="badge degraded" title={reason.clone()}>{"DEGRADED"}</span>
+                                            },
+                                            None => html! {},
+                                        }
+                                    }
+                                    {
+                                        if tracee.warnings.is_empty() {
+                                            html! {}
+                                        } else {
+                                            html! {
+                                                <span class="badge warnings" title={tracee.warnings.join("\n")}>{format!("{} warnings", tracee.warnings.len())}</span>
+                                            }
+                                        }
+                                    }
+                                    {
+                                        match tracee.parent {
+                                            Some(parent) => html! {
+                                                <span class="badge forked" title="Forked from that traced process, and shares memory with it copy-on-write">{format!("forked from {}", parent.0)}</span>
+                                            },
+                                            None => html! {},
+                                        }
+                                    }
+                                    {
+                                        match tracee.usage {
+                                            Some(usage) => html! {
+                                                <span class="badge usage" title="As the kernel counts it, including memory mevi doesn't track">{format!("RSS {} · PSS {} · USS {}", formatter(usage.rss), formatter(usage.pss), formatter(usage.uss))}</span>
+                                            },
+                                            None => html! {},
+                                        }
+                                    }
+                                    {
+                                        match tracee.drift {
+                                            Some(drift) => {
+                                                let sign = if drift < 0 { "-" } else { "+" };
+                                                html! {
+                                                    <span class="badge drift" title="How much more memory mevi shows as resident than the kernel counts">{format!("drift {sign}{}", formatter(drift.unsigned_abs()))}</span>
+                                                }
+                                            }
+                                            None => html! {},
+                                        }
+                                    }
+                                    {
+                                        if options.color_by_thread {
+                                            tracee.touched_bytes.iter().sorted_by_key(|(_, bytes)| std::cmp::Reverse(**bytes)).map(|(thread, bytes)| {
+                                                let name = if *thread == tracee.tid { "main".to_owned() } else { format!("thread {}", thread.0) };
+                                                html! {
+                                                    <span class="badge thread" style={format!("background-color:{}", thread_color(*thread))} title="Faulted in by this thread, over the process's lifetime">{format!("{name}: {}", formatter(*bytes))}</span>
+                                                }
+                                            }).collect::<Html>()
+                                        } else {
+                                            html! {}
+                                        }
+                                    }
+                                    {
+                                        tracee.node_bytes().into_iter().map(|(node, bytes)| {
+                                            html! {
+                                                <span class="badge numa-node" style={format!("background-color:{}", node_color(node))} title="Resident on this NUMA node">{format!("node {node}: {}", formatter(bytes))}</span>
+                                            }
+                                        }).collect::<Html>()
+                                    }
                                     {
                                         tracee.cmdline.iter().map(|arg| {
                                             html! {
@@ -232,7 +945,7 @@ fn app() -> Html {
                                 </div>
                                 {{
                                     let map = &tracee.map;
-                                    let has_any_memory_resident = map.iter().any(|(_, state)| *state == MemState::Resident);
+                                    let has_any_memory_resident = map.iter().any(|(_, state)| state.is_resident());
                                     if !has_any_memory_resident {
                                         return html!{ };
                                     }
@@ -241,29 +954,26 @@ fn app() -> Html {
                                     let mut groups: Vec<Group> = vec![];
                                     // let threshold_new_group = 4 * 1024 * 1024;
                                     let threshold_new_group = 128 * 1024 * 1024;
-                                    for (range, state) in map.iter() {
+                                    for span in tracee.spans(options.color_by_thread, options.color_by_numa_node) {
                                         num_ranges += 1;
+                                        let range = span.range.clone();
                                         if let Some(last_group) = groups.last() {
                                             if range.start - (last_group.start + last_group.size) > threshold_new_group || last_group.size >= 30 * 1024 * 1024 {
                                                 groups.push(Group {
                                                     start: range.start,
                                                     size: range.end - range.start,
-                                                    ranges: vec![
-                                                        (range.clone(), *state)
-                                                    ],
+                                                    spans: vec![span],
                                                 });
                                             } else {
                                                 let last_group = groups.last_mut().unwrap();
-                                                last_group.ranges.push((range.clone(), *state));
+                                                last_group.spans.push(span);
                                                 last_group.size = range.end - last_group.start;
                                             }
                                         } else {
                                             groups.push(Group {
                                                 start: range.start,
                                                 size: range.end - range.start,
-                                                ranges: vec![
-                                                    (range.clone(), *state)
-                                                ],
+                                                spans: vec![span],
                                             });
                                         }
                                     }
@@ -274,7 +984,7 @@ fn app() -> Html {
                                     for group in groups {
                                         let mut group_markup = vec![];
 
-                                        let has_any_memory_resident = group.ranges.iter().any(|(_, state)| *state == MemState::Resident);
+                                        let has_any_memory_resident = group.spans.iter().any(|span| span.state.is_resident());
                                         if !has_any_memory_resident && !options.show_nonresident_groups {
                                             continue;
                                         }
@@ -303,7 +1013,7 @@ fn app() -> Html {
                                             min_size_for_show = 6 * 4096;
                                         }
 
-                                        for (range, mem_state) in group.ranges {
+                                        for Span { range, state: mem_state, thread, cold, zero_filled, duplicate, node, attrs } in group.spans {
                                             let size = range.end - range.start;
                                             if size < min_size_for_show {
                                                 continue;
@@ -315,19 +1025,67 @@ fn app() -> Html {
                                                     MemState::Resident => "r",
                                                     MemState::NotResident => "n",
                                                     MemState::Untracked => "u",
+                                                    MemState::Prefaulted => "p",
+                                                    MemState::ZeroPage => "z",
+                                                    MemState::HugeResident => "h",
+                                                    MemState::Swapped => "s",
+                                                    MemState::LazyFree => "l",
+                                                    MemState::SharedCow => "c",
                                                 }
                                             };
 
-                                            let style = format!("width:{}%;left:{}%;", size as f64 * scale_ratio, (range.start - group.start) as f64 * scale_ratio);
+                                            let mut style = format!("width:{}%;left:{}%;", size as f64 * scale_ratio, (range.start - group.start) as f64 * scale_ratio);
+                                            let mut title = format!("{} at {:x?}", formatter(size), range);
+                                            if let Some(thread) = thread {
+                                                style.push_str(&format!("background-color:{};", thread_color(thread)));
+                                                title.push_str(&format!(", first touched by thread {}", thread.0));
+                                            }
+                                            if let Some(node) = node {
+                                                style.push_str(&format!("background-color:{};", node_color(node)));
+                                                title.push_str(&format!(", on NUMA node {node}"));
+                                            }
+                                            if cold {
+                                                title.push_str(", not written to recently");
+                                            }
+                                            if zero_filled {
+                                                title.push_str(", all zeros");
+                                            }
+                                            if duplicate {
+                                                title.push_str(", duplicated");
+                                            }
+                                            let advice_class = match attrs.huge_pages {
+                                                HugePageAdvice::None => None,
+                                                HugePageAdvice::Huge => {
+                                                    title.push_str(", MADV_HUGEPAGE");
+                                                    Some("advised-huge")
+                                                }
+                                                HugePageAdvice::NoHuge => {
+                                                    title.push_str(", MADV_NOHUGEPAGE");
+                                                    Some("advised-nohuge")
+                                                }
+                                            };
+                                            if attrs.locked {
+                                                title.push_str(", locked");
+                                            }
+                                            if let Some((mapping_range, mapping)) = mapping_at(&tracee.mappings, range.start) {
+                                                title.push_str(&format!("\nin {} at {:x?}", describe_mapping(mapping), mapping_range));
+                                            }
+                                            let onclick = mem_state.is_resident().then(|| {
+                                                title.push_str(", click to inspect");
+                                                let inspect = inspect.clone();
+                                                let (tid, addr) = (tracee.tid, range.start);
+                                                Callback::from(move |_| inspect.emit(InspectRequest { tid, addr }))
+                                            });
+                                            let class = classes!(state_class(mem_state), cold.then_some("cold"), zero_filled.then_some("zero-filled"), duplicate.then_some("duplicate"), advice_class, attrs.locked.then_some("locked"));
                                             let h = if size >= min_size_for_print {
                                                 html! {
-                                                    <i class={state_class(mem_state)} title={format!("{} at {:x?}", formatter(size), range)} style={style}>{
+                                                    <i class={class} title={title} style={style} {onclick}>{
                                                         formatter(size).to_string()
                                                     }</i>
                                                 }
                                             } else {
                                                 html! {
-                                                    <i class={state_class(mem_state)} style={style}></i>
+                                                    <i class={class} style={style} {onclick}></i>
                                                 }
                                             };
                                             group_markup.push(h)
@@ -382,9 +1140,15 @@ fn app() -> Html {
     }
 }
 
-fn apply_ev(tracees: &mut HashMap<TraceeId, TraceeState>, ev: MeviEvent) {
+fn apply_ev(
+    tracees: &mut HashMap<TraceeId, TraceeState>,
+    console: &mut Console,
+    ev: MeviEvent,
+) {
     let (tid, payload) = match ev {
         MeviEvent::Snapshot(snap_tracees) => {
+            // the server replays its output backlog right after a snapshot
+            console.clear();
             for snap_tracee in snap_tracees {
                 let tracee = tracees
                     .entry(snap_tracee.tid)
@@ -392,26 +1156,122 @@ fn apply_ev(tracees: &mut HashMap<TraceeId, TraceeState>, ev: MeviEvent) {
                         tid: snap_tracee.tid,
                         map: Default::default(),
                         cmdline: Default::default(),
+                        degraded: Default::default(),
+                        warnings: Default::default(),
+                        drift: Default::default(),
+                        touches: Default::default(),
+                        touched_bytes: Default::default(),
+                        working_set: Default::default(),
+                        attrs: Default::default(),
+                        parent: Default::default(),
+                        usage: Default::default(),
+                        zero_filled: Default::default(),
+                        duplicates: Default::default(),
+                        numa_nodes: Default::default(),
+                        mappings: Default::default(),
                     });
                 tracee.cmdline = snap_tracee.cmdline;
                 tracee.map = snap_tracee.map;
+                tracee.degraded = snap_tracee.degraded;
+                tracee.warnings = snap_tracee.warnings;
+                tracee.drift = snap_tracee.drift;
+                tracee.touches = snap_tracee.touches;
+                tracee.touched_bytes = snap_tracee.touched_bytes;
+                tracee.working_set = snap_tracee.working_set;
+                tracee.attrs = snap_tracee.attrs;
+                tracee.parent = snap_tracee.parent;
+                tracee.usage = snap_tracee.usage;
+                tracee.zero_filled = snap_tracee.zero_filled;
+                tracee.duplicates = snap_tracee.duplicates;
+                tracee.numa_nodes = snap_tracee.numa_nodes;
+                tracee.mappings = snap_tracee.mappings;
             }
             return;
         }
         MeviEvent::TraceeEvent(tid, ev) => (tid, ev),
+        MeviEvent::Output(chunk) => {
+            let rss = tracees.values().map(|t| t.total_rss()).sum();
+            apply_output(console, chunk, rss);
+            return;
+        }
+        MeviEvent::Inspection(_) => {
+            // not part of the tracee state, the app handles those
+            return;
+        }
+    };
+
+    let inherited = match &payload {
+        TraceePayload::Forked { parent } => tracees.get(parent).map(|p| forked_memmap(&p.map)),
+        _ => None,
     };
 
     let tracee = tracees.entry(tid).or_insert_with(|| TraceeState {
         tid,
         map: Default::default(),
         cmdline: Default::default(),
+        degraded: Default::default(),
+        warnings: Default::default(),
+        drift: Default::default(),
+        touches: Default::default(),
+        touched_bytes: Default::default(),
+        working_set: Default::default(),
+        attrs: Default::default(),
+        parent: Default::default(),
+        usage: Default::default(),
+        zero_filled: Default::default(),
+        duplicates: Default::default(),
+        numa_nodes: Default::default(),
+        mappings: Default::default(),
     });
 
     payload.apply_to_memmap(&mut tracee.map);
+    payload.apply_to_touches(&mut tracee.touches);
+    payload.apply_to_attrs(&mut tracee.attrs);
+    payload.apply_to_mappings(&mut tracee.mappings);
     match payload {
         TraceePayload::CmdLineChange { cmdline } => {
             tracee.cmdline = cmdline;
         }
+        TraceePayload::Degraded { reason } => {
+            tracee.degraded = Some(reason);
+        }
+        TraceePayload::Warning { message } => {
+            if tracee.warnings.len() == MAX_WARNINGS {
+                tracee.warnings.remove(0);
+            }
+            tracee.warnings.push(message);
+        }
+        TraceePayload::Drift { bytes } => {
+            tracee.drift = Some(bytes);
+        }
+        TraceePayload::Forked { parent } => {
+            tracee.parent = Some(parent);
+            if let Some(map) = inherited {
+                tracee.map = map;
+            }
+        }
+        TraceePayload::Usage(usage) => {
+            tracee.usage = Some(usage);
+        }
+        TraceePayload::WorkingSet(working_set) => {
+            tracee.working_set = Some(working_set);
+        }
+        TraceePayload::ZeroFilled(zero_filled) => {
+            tracee.zero_filled = Some(zero_filled);
+        }
+        TraceePayload::Duplicates(duplicates) => {
+            tracee.duplicates = Some(duplicates);
+        }
+        TraceePayload::NumaNodes(nodes) => {
+            tracee.numa_nodes = Some(nodes);
+        }
+        TraceePayload::PageFault {
+            range,
+            thread: Some(thread),
+            ..
+        } => {
+            *tracee.touched_bytes.entry(thread).or_default() += range.end - range.start;
+        }
         TraceePayload::Exit { .. } => {
             tracees.remove(&tid);
         }
@@ -421,6 +1281,96 @@ fn apply_ev(tracees: &mut HashMap<TraceeId, TraceeState>, ev: MeviEvent) {
     }
 }
 
+fn apply_output(console: &mut Console, chunk: OutputChunk, rss: u64) {
+    let mut data = console.pending.remove(&chunk.stream).unwrap_or_default();
+    data.extend_from_slice(&chunk.data);
+    let (text, rest) = decode_utf8(&data);
+    if !rest.is_empty() {
+        console.pending.insert(chunk.stream, rest.to_vec());
+    }
+    let text = strip_ansi(&text);
+    let num_newlines = text.matches('\n').count();
+    for (i, piece) in text.split('\n').enumerate() {
+        let ends_line = i < num_newlines;
+        if piece.is_empty() && !ends_line {
+            continue;
+        }
+
+        // continue the last line if it was cut in the middle
+        match console.lines.last_mut() {
+            Some(last) if !last.complete && last.stream == chunk.stream => {
+                last.text.push_str(piece);
+                last.complete = ends_line;
+            }
+            _ => {
+                console.lines.push(ConsoleLine {
+                    stream: chunk.stream,
+                    elapsed_ms: chunk.elapsed_ms,
+                    rss,
+                    text: piece.to_owned(),
+                    complete: ends_line,
+                });
+            }
+        }
+    }
+
+    if console.lines.len() > CONSOLE_MAX_LINES {
+        console.lines.drain(..console.lines.len() - CONSOLE_MAX_LINES);
+    }
+}
+
+/// Like `String::from_utf8_lossy`, except an incomplete sequence at the end is
+/// returned as-is: the rest of it is in the next chunk.
+fn decode_utf8(mut data: &[u8]) -> (String, &[u8]) {
+    let mut out = String::with_capacity(data.len());
+    loop {
+        match std::str::from_utf8(data) {
+            Ok(s) => {
+                out.push_str(s);
+                return (out, &[]);
+            }
+            Err(e) => {
+                let (valid, rest) = data.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(valid).unwrap());
+                match e.error_len() {
+                    Some(len) => {
+                        out.push(char::REPLACEMENT_CHARACTER);
+                        data = &rest[len..];
+                    }
+                    None => return (out, rest),
+                }
+            }
+        }
+    }
+}
+
+/// Drops carriage returns and terminal escape sequences, which ptys are
+/// full of.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => {}
+            '\x1b' => {
+                if chars.peek() == Some(&'[') {
+                    chars.next();
+                    // CSI: parameters, then a final byte in '@'..='~'
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                } else {
+                    chars.next();
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
 fn main() {
     yew::Renderer::<App>::new().render();
 }
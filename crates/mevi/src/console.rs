@@ -0,0 +1,139 @@
+use std::{
+    fs::File,
+    io::Read,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    time::Instant,
+};
+
+use color_eyre::Result;
+use mevi_common::{MeviEvent, OutputChunk, OutputStream};
+use nix::pty::openpty;
+use tracing::{debug, trace, warn};
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum StdioMode {
+    /// The tracee shares mevi's terminal, and its output is not forwarded
+    Inherit,
+
+    /// The tracee gets a pseudo-terminal of its own, as its controlling
+    /// terminal, so programs that check `isatty` behave like they would
+    /// interactively
+    Pty,
+
+    /// The tracee's stdout and stderr are pipes, so they can be told apart
+    Pipes,
+}
+
+/// The mevi side of the tracee's stdio
+pub(crate) enum Console {
+    Inherit,
+    Pty { master: OwnedFd },
+    Pipes,
+}
+
+impl Console {
+    /// Sets up stdio for `cmd`, which must not have been spawned yet.
+    pub(crate) fn prepare(cmd: &mut Command, mode: StdioMode) -> Result<Self> {
+        match mode {
+            StdioMode::Inherit => Ok(Self::Inherit),
+            StdioMode::Pty => {
+                let pty = openpty(None, None)?;
+                // neither end should leak into the tracee past the stdio
+                // descriptors `Command` sets up
+                set_cloexec(&pty.master);
+                set_cloexec(&pty.slave);
+
+                cmd.stdin(Stdio::from(pty.slave.try_clone()?));
+                cmd.stdout(Stdio::from(pty.slave.try_clone()?));
+                cmd.stderr(Stdio::from(pty.slave));
+
+                unsafe {
+                    cmd.pre_exec(|| {
+                        // make the pty the controlling terminal of a new
+                        // session, stdin is the slave end at this point
+                        nix::unistd::setsid()?;
+                        if libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+
+                Ok(Self::Pty { master: pty.master })
+            }
+            StdioMode::Pipes => {
+                cmd.stdout(Stdio::piped());
+                cmd.stderr(Stdio::piped());
+                Ok(Self::Pipes)
+            }
+        }
+    }
+
+    /// Starts forwarding the tracee's output as [MeviEvent::Output] events,
+    /// timestamped relative to `start`.
+    pub(crate) fn forward(
+        self,
+        child: &mut Child,
+        tx: &mpsc::SyncSender<MeviEvent>,
+        start: Instant,
+    ) {
+        match self {
+            Console::Inherit => {}
+            Console::Pty { master } => {
+                spawn_forwarder(File::from(master), OutputStream::Pty, tx.clone(), start);
+            }
+            Console::Pipes => {
+                if let Some(stdout) = child.stdout.take() {
+                    spawn_forwarder(stdout, OutputStream::Stdout, tx.clone(), start);
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    spawn_forwarder(stderr, OutputStream::Stderr, tx.clone(), start);
+                }
+            }
+        }
+    }
+}
+
+fn set_cloexec(fd: &OwnedFd) {
+    unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+}
+
+fn spawn_forwarder(
+    mut r: impl Read + Send + 'static,
+    stream: OutputStream,
+    tx: mpsc::SyncSender<MeviEvent>,
+    start: Instant,
+) {
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                // that's how a pty master says every slave was closed
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                Err(e) => {
+                    warn!("while reading tracee {stream:?}: {e}");
+                    break;
+                }
+            };
+            trace!("{stream:?}: {:?}", String::from_utf8_lossy(&buf[..n]));
+
+            let chunk = OutputChunk {
+                stream,
+                elapsed_ms: start.elapsed().as_millis() as u64,
+                data: buf[..n].to_vec(),
+            };
+            if tx.send(MeviEvent::Output(chunk)).is_err() {
+                break;
+            }
+        }
+        debug!("tracee {stream:?} closed");
+    });
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    ops::Range,
+    sync::mpsc,
+};
+
+use mevi_common::{Duplicates, MappingBytes, MeviEvent, TraceeId, TraceePayload, ZeroFilled};
+use nix::unistd::Pid;
+use procfs::process::MMapPath;
+use rangemap::RangeSet;
+use tracing::debug;
+
+use crate::{pagemap::Scanner, remote};
+
+/// How much we read from a tracee at once
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Resident ranges of every tracee we want looked at
+type Job = Vec<(TraceeId, Vec<Range<u64>>)>;
+
+/// A page of a tracee, by address
+type PageAt = (TraceeId, u64);
+
+/// Reads through tracees' resident memory to see what's in it.
+///
+/// Only pages pagemap says are present get read, and not the ones backed by
+/// the shared zero page, which cost nothing. This still runs on its own
+/// thread: a page reclaimed in between gets faulted back in, and if it's
+/// registered with userfaultfd, that blocks until the uffd loop handles it.
+#[derive(Clone)]
+pub(crate) struct ContentScanner {
+    jobs: mpsc::SyncSender<Job>,
+}
+
+impl ContentScanner {
+    pub(crate) fn spawn(tx: mpsc::SyncSender<MeviEvent>) -> Self {
+        let (jobs, jobs_rx) = mpsc::sync_channel::<Job>(1);
+        std::thread::spawn(move || {
+            let mut last: HashMap<TraceeId, (ZeroFilled, Duplicates)> = Default::default();
+            let mut scanner = Scanner::new();
+            for job in jobs_rx {
+                let scanned: BTreeMap<TraceeId, Contents> = job
+                    .into_iter()
+                    .filter_map(|(tid, ranges)| Some((tid, scan(tid, &ranges, &mut scanner)?)))
+                    .collect();
+                let mut duplicates = find_duplicates(&scanned, read_page);
+                last.retain(|tid, _| scanned.contains_key(tid));
+
+                for (tid, contents) in scanned {
+                    let mut dupes = duplicates.remove(&tid).unwrap_or_default();
+                    dupes.per_mapping = contents.per_mapping(&dupes.pages);
+                    let zero_filled = ZeroFilled {
+                        per_mapping: contents.per_mapping(&contents.zeros),
+                        pages: contents.zeros,
+                    };
+
+                    let prev = last.get(&tid);
+                    let mut payloads = vec![];
+                    if prev.map(|(z, _)| z) != Some(&zero_filled) {
+                        payloads.push(TraceePayload::ZeroFilled(zero_filled.clone()));
+                    }
+                    if prev.map(|(_, d)| d) != Some(&dupes) {
+                        payloads.push(TraceePayload::Duplicates(dupes.clone()));
+                    }
+                    last.insert(tid, (zero_filled, dupes));
+
+                    for payload in payloads {
+                        if tx.send(MeviEvent::TraceeEvent(tid, payload)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Has resident memory scanned, unless the previous scan is still going,
+    /// in which case there's no point piling up more work.
+    pub(crate) fn request(&self, job: Job) {
+        if self.jobs.try_send(job).is_err() {
+            debug!("content scan still running, skipping this one");
+        }
+    }
+}
+
+/// What one tracee's resident memory looked like
+struct Contents {
+    zeros: RangeSet<u64>,
+    /// A hash of each page worth deduplicating, by address
+    hashes: Vec<(u64, u64)>,
+    /// Everything in `/proc/PID/maps`, by name
+    mappings: Vec<(Range<u64>, String)>,
+}
+
+impl Contents {
+    /// How much of `pages` is in each mapping, for mappings that have any
+    fn per_mapping(&self, pages: &RangeSet<u64>) -> Vec<MappingBytes> {
+        per_mapping(&self.mappings, pages)
+    }
+}
+
+/// How much of `pages` is in each of `mappings`, for mappings that have any
+pub(crate) fn per_mapping(
+    mappings: &[(Range<u64>, String)],
+    pages: &RangeSet<u64>,
+) -> Vec<MappingBytes> {
+    mappings
+        .iter()
+        .filter_map(|(range, name)| {
+            let bytes: u64 = pages
+                .overlapping(range)
+                .map(|r| r.end.min(range.end) - r.start.max(range.start))
+                .sum();
+            (bytes > 0).then(|| MappingBytes {
+                range: range.clone(),
+                name: name.clone(),
+                bytes,
+            })
+        })
+        .collect()
+}
+
+/// Reads resident memory from `ranges`, `None` if the tracee went away.
+fn scan(tid: TraceeId, ranges: &[Range<u64>], scanner: &mut Scanner) -> Option<Contents> {
+    let pid = Pid::from_raw(tid.0 as _);
+    let page_size = procfs::page_size();
+
+    let p = procfs::process::Process::new(tid.0 as _).ok()?;
+    let maps = p.maps().ok()?;
+    let mut anon: RangeSet<u64> = Default::default();
+    let mut mappings = vec![];
+    for map in maps.iter() {
+        let range = map.address.0..map.address.1;
+        if let MMapPath::Anonymous | MMapPath::Heap | MMapPath::Stack | MMapPath::TStack(_) =
+            map.pathname
+        {
+            anon.insert(range.clone());
+        }
+        mappings.push((range, mapping_name(&map.pathname)));
+    }
+
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut contents = Contents {
+        zeros: Default::default(),
+        hashes: Default::default(),
+        mappings,
+    };
+    for resident in ranges {
+        // we may not have heard about it being swapped out or reclaimed yet,
+        // or it may only have been read, which maps the zero page
+        let backed = scanner.backed(&p, resident.clone()).ok()?;
+        for (range, exclusive) in backed {
+            let mut addr = range.start;
+            while addr < range.end {
+                let len = (range.end - addr).min(CHUNK_SIZE);
+                let chunk = &mut buf[..len as usize];
+                match remote::read(pid, addr as _, chunk) {
+                    Ok(()) => {
+                        for (i, page) in chunk.chunks(page_size as _).enumerate() {
+                            let start = addr + i as u64 * page_size;
+                            if page.iter().all(|&b| b == 0) {
+                                contents.zeros.insert(start..start + page.len() as u64);
+                            }
+                            // pages shared copy-on-write are already deduplicated
+                            if exclusive && anon.contains(&start) {
+                                let mut hasher = DefaultHasher::new();
+                                page.hash(&mut hasher);
+                                contents.hashes.push((start, hasher.finish()));
+                            }
+                        }
+                    }
+                    Err(nix::errno::Errno::ESRCH) => return None,
+                    Err(e) => {
+                        // it was unmapped while we weren't looking
+                        debug!("{tid} couldn't read {addr:#x}..{:#x}: {e}", addr + len);
+                    }
+                }
+                addr += len;
+            }
+        }
+    }
+    Some(contents)
+}
+
+/// Reads one page again, to compare it with others
+fn read_page(tid: TraceeId, addr: u64) -> Option<Vec<u8>> {
+    let mut page = vec![0u8; procfs::page_size() as usize];
+    remote::read(Pid::from_raw(tid.0 as _), addr as _, &mut page).ok()?;
+    Some(page)
+}
+
+/// Finds pages with identical contents, within and across tracees.
+///
+/// Hashes only narrow it down: pages with the same hash are read again with
+/// `read`, and only count as duplicates if their contents are the same.
+///
+/// Merging them would keep one copy of each: we say it's the one in the
+/// lowest-numbered tracee, so that `mergeable` adds up across tracees.
+fn find_duplicates(
+    scanned: &BTreeMap<TraceeId, Contents>,
+    mut read: impl FnMut(TraceeId, u64) -> Option<Vec<u8>>,
+) -> HashMap<TraceeId, Duplicates> {
+    let page_size = procfs::page_size();
+    let mut owners: HashMap<u64, Vec<PageAt>> = Default::default();
+    for (tid, contents) in scanned {
+        for &(addr, hash) in &contents.hashes {
+            owners.entry(hash).or_default().push((*tid, addr));
+        }
+    }
+
+    // a 64-bit hash collides eventually, and pages may have changed since
+    let mut confirmed: Vec<Vec<PageAt>> = vec![];
+    for candidates in owners.values().filter(|pages| pages.len() > 1) {
+        let mut same: Vec<(Vec<u8>, Vec<PageAt>)> = vec![];
+        for &(tid, addr) in candidates {
+            let Some(page) = read(tid, addr) else {
+                continue;
+            };
+            match same.iter_mut().find(|(contents, _)| *contents == page) {
+                Some((_, pages)) => pages.push((tid, addr)),
+                None => same.push((page, vec![(tid, addr)])),
+            }
+        }
+        confirmed.extend(
+            same.into_iter()
+                .map(|(_, pages)| pages)
+                .filter(|pages| pages.len() > 1),
+        );
+    }
+
+    let mut duplicates: HashMap<TraceeId, Duplicates> = Default::default();
+    let mut with: HashMap<TraceeId, BTreeMap<TraceeId, u64>> = Default::default();
+    for pages in &confirmed {
+        let mut counts: BTreeMap<TraceeId, u64> = Default::default();
+        for (i, &(tid, addr)) in pages.iter().enumerate() {
+            let dupes = duplicates.entry(tid).or_default();
+            dupes.pages.insert(addr..addr + page_size);
+            if i > 0 {
+                dupes.mergeable += page_size;
+            }
+            *counts.entry(tid).or_default() += 1;
+        }
+        for (&a, &count_a) in &counts {
+            if count_a > 1 {
+                duplicates.entry(a).or_default().within += (count_a - 1) * page_size;
+            }
+            for (&b, &count_b) in counts.iter().filter(|(b, _)| **b != a) {
+                *with.entry(a).or_default().entry(b).or_default() +=
+                    count_a.min(count_b) * page_size;
+            }
+        }
+    }
+    for (tid, with) in with {
+        duplicates.entry(tid).or_default().with = with.into_iter().collect();
+    }
+    duplicates
+}
+
+/// What `/proc/PID/maps` would show for a mapping
+pub(crate) fn mapping_name(path: &MMapPath) -> String {
+    match path {
+        MMapPath::Path(p) => p.display().to_string(),
+        MMapPath::Heap => "[heap]".into(),
+        MMapPath::Stack => "[stack]".into(),
+        MMapPath::TStack(tid) => format!("[stack:{tid}]"),
+        MMapPath::Vdso => "[vdso]".into(),
+        MMapPath::Vvar => "[vvar]".into(),
+        MMapPath::Vsyscall => "[vsyscall]".into(),
+        MMapPath::Rollup => "[rollup]".into(),
+        MMapPath::Anonymous => "[anon]".into(),
+        MMapPath::Vsys(key) => format!("/SYSV{key:08x}"),
+        MMapPath::Other(s) => s.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(hashes: &[(u64, u64)]) -> Contents {
+        Contents {
+            zeros: Default::default(),
+            hashes: hashes.to_vec(),
+            mappings: vec![],
+        }
+    }
+
+    /// Every page reads as its hash, except for `differ`, which collided
+    fn reader(
+        scanned: &BTreeMap<TraceeId, Contents>,
+        differ: &[PageAt],
+    ) -> impl FnMut(TraceeId, u64) -> Option<Vec<u8>> {
+        let pages: HashMap<PageAt, u64> = scanned
+            .iter()
+            .flat_map(|(tid, c)| c.hashes.iter().map(|&(addr, hash)| ((*tid, addr), hash)))
+            .collect();
+        let differ = differ.to_vec();
+        move |tid, addr| {
+            let hash = pages[&(tid, addr)];
+            let mut page = hash.to_le_bytes().to_vec();
+            if differ.contains(&(tid, addr)) {
+                page.push(1);
+            }
+            Some(page)
+        }
+    }
+
+    #[test]
+    fn counts_within_and_across_tracees() {
+        let ps = procfs::page_size();
+        let (a, b) = (TraceeId(1), TraceeId(2));
+        let mut scanned = BTreeMap::new();
+        // hash 7: twice in a, once in b. hash 9: once in each.
+        scanned.insert(a, contents(&[(0, 7), (ps, 7), (2 * ps, 9), (3 * ps, 5)]));
+        scanned.insert(b, contents(&[(0, 7), (ps, 9)]));
+
+        let dupes = find_duplicates(&scanned, reader(&scanned, &[]));
+
+        let da = &dupes[&a];
+        assert_eq!(
+            da.pages.iter().cloned().collect::<Vec<_>>(),
+            vec![0..3 * ps]
+        );
+        // the first copy of each is kept, and a comes first
+        assert_eq!(da.mergeable, ps);
+        assert_eq!(da.within, ps);
+        assert_eq!(da.with, vec![(b, 2 * ps)]);
+
+        let db = &dupes[&b];
+        assert_eq!(
+            db.pages.iter().cloned().collect::<Vec<_>>(),
+            vec![0..2 * ps]
+        );
+        assert_eq!(db.mergeable, 2 * ps);
+        assert_eq!(db.within, 0);
+        assert_eq!(db.with, vec![(a, 2 * ps)]);
+
+        // all that merging would free: 5 duplicated pages, 2 distinct contents
+        assert_eq!(da.mergeable + db.mergeable, 3 * ps);
+    }
+
+    #[test]
+    fn hash_collisions_are_not_duplicates() {
+        let ps = procfs::page_size();
+        let a = TraceeId(1);
+        let mut scanned = BTreeMap::new();
+        scanned.insert(a, contents(&[(0, 7), (ps, 7), (2 * ps, 7)]));
+
+        let dupes = find_duplicates(&scanned, reader(&scanned, &[(a, ps)]));
+        let da = &dupes[&a];
+        assert_eq!(
+            da.pages.iter().cloned().collect::<Vec<_>>(),
+            vec![0..ps, 2 * ps..3 * ps]
+        );
+        assert_eq!(da.mergeable, ps);
+        assert_eq!(da.within, ps);
+
+        // and a lone page with a colliding hash isn't one at all
+        let mut scanned = BTreeMap::new();
+        scanned.insert(a, contents(&[(0, 7), (ps, 7)]));
+        let dupes = find_duplicates(&scanned, reader(&scanned, &[(a, ps)]));
+        assert!(dupes.is_empty());
+    }
+
+    #[test]
+    fn unreadable_pages_are_skipped() {
+        let ps = procfs::page_size();
+        let a = TraceeId(1);
+        let mut scanned = BTreeMap::new();
+        scanned.insert(a, contents(&[(0, 7), (ps, 7)]));
+
+        let dupes = find_duplicates(&scanned, |_, addr| (addr == 0).then(|| vec![0]));
+        assert!(dupes.is_empty());
+    }
+}
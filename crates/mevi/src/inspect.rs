@@ -0,0 +1,178 @@
+use std::{
+    fmt::Write,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mevi_common::{InspectRequest, Inspection, PageContents, PointerGuess, TraceeId, MAX_GUESSES};
+use nix::unistd::Pid;
+use tracing::debug;
+
+use crate::{content::mapping_name, pagemap::Scanner, remote, userfault::UffdLoop};
+
+/// Frontends can't have us read pages more often than this, all together
+const INSPECT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Reads pages of tracees for frontends, see [InspectRequest]
+#[derive(Clone)]
+pub(crate) struct Inspector {
+    enabled: bool,
+    uffds: UffdLoop,
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Inspector {
+    pub(crate) fn new(enabled: bool, uffds: UffdLoop) -> Self {
+        Self {
+            enabled,
+            uffds,
+            last: Default::default(),
+        }
+    }
+
+    /// `from_frontend` is false for requests from pages other than the
+    /// frontend, which we refuse.
+    pub(crate) async fn inspect(&self, req: InspectRequest, from_frontend: bool) -> Inspection {
+        let page_size = procfs::page_size();
+        let addr = req.addr / page_size * page_size;
+        let result = match self.check(req.tid, from_frontend) {
+            Ok(()) => {
+                let tid = req.tid;
+                tokio::task::spawn_blocking(move || read_page(tid, addr, page_size))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+            }
+            Err(e) => Err(e),
+        };
+        Inspection {
+            tid: req.tid,
+            addr,
+            page_size,
+            result,
+        }
+    }
+
+    fn check(&self, tid: TraceeId, from_frontend: bool) -> Result<(), String> {
+        if !self.enabled {
+            return Err("the page inspector is off, pass --inspect to turn it on".into());
+        }
+        if !from_frontend {
+            return Err("only the frontend can inspect pages".into());
+        }
+        // only our tracees: mevi may be able to read a lot more than that
+        if !self.uffds.is_watched(tid) {
+            return Err(format!("{tid} isn't traced"));
+        }
+        let mut last = self.last.lock().unwrap();
+        if last.is_some_and(|at| at.elapsed() < INSPECT_INTERVAL) {
+            return Err("too many requests, try again in a bit".into());
+        }
+        *last = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Reads a page, and guesses what's in it.
+///
+/// Only pages that are in memory: reading any other would fault it in,
+/// which changes what's being looked at. Not from an async task either: the
+/// page may have been reclaimed since we checked, and if it's registered
+/// with userfaultfd, reading it blocks until the uffd loop fills it in.
+fn read_page(tid: TraceeId, addr: u64, page_size: u64) -> Result<PageContents, String> {
+    debug!("{tid} inspecting page at {addr:#x}");
+    let p = procfs::process::Process::new(tid.0 as _).map_err(|e| e.to_string())?;
+    let present = Scanner::new()
+        .present(&p, addr..addr + page_size)
+        .map_err(|e| e.to_string())?;
+    if present.is_empty() {
+        return Err(format!("{addr:#x} isn't resident"));
+    }
+
+    let mut page = vec![0u8; page_size as usize];
+    remote::read(Pid::from_raw(tid.0 as _), addr as _, &mut page).map_err(|e| e.to_string())?;
+
+    let mappings: Vec<(Range<u64>, String)> = p
+        .maps()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|map| (map.address.0..map.address.1, mapping_name(&map.pathname)))
+        .collect();
+
+    let pointers = page
+        .chunks(std::mem::size_of::<u64>())
+        .enumerate()
+        .filter_map(|(i, word)| {
+            let value = u64::from_ne_bytes(word.try_into().unwrap());
+            let (_, mapping) = mappings.iter().find(|(range, _)| range.contains(&value))?;
+            Some(PointerGuess {
+                offset: (i * word.len()) as u64,
+                value,
+                mapping: mapping.clone(),
+            })
+        })
+        .take(MAX_GUESSES)
+        .collect();
+
+    Ok(PageContents {
+        hexdump: hexdump(&page),
+        zeros: page.iter().all(|&b| b == 0),
+        pointers,
+        strings: strings(&page),
+    })
+}
+
+/// Like `hexdump -C`
+fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut prev: Option<&[u8]> = None;
+    let mut repeated = false;
+    for (i, line) in data.chunks(16).enumerate() {
+        if prev == Some(line) {
+            if !repeated {
+                out.push_str("*\n");
+                repeated = true;
+            }
+            continue;
+        }
+        prev = Some(line);
+        repeated = false;
+
+        _ = write!(out, "{:08x} ", i * 16);
+        for (j, b) in line.iter().enumerate() {
+            if j % 8 == 0 {
+                out.push(' ');
+            }
+            _ = write!(out, "{b:02x} ");
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    _ = writeln!(out, "{:08x}", data.len());
+    out
+}
+
+/// Runs of printable UTF-8 that are long enough to mean something
+fn strings(data: &[u8]) -> Vec<(u64, String)> {
+    let mut found = vec![];
+    let mut offset = 0;
+    for run in data.split(|&b| b < 0x20 && b != b'\t' && b != b'\n' || b == 0x7f) {
+        if let Ok(s) = std::str::from_utf8(run) {
+            if s.trim().chars().count() >= 4 {
+                found.push((offset as u64, s.to_string()));
+                if found.len() == MAX_GUESSES {
+                    break;
+                }
+            }
+        }
+        offset += run.len() + 1;
+    }
+    found
+}
@@ -1,22 +1,49 @@
-use std::{collections::HashMap, os::unix::net::UnixListener, sync::mpsc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    os::unix::net::UnixListener,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
 
 use axum::{
     extract::{
         ws::{Message, WebSocket},
         State, WebSocketUpgrade,
     },
-    response::IntoResponse,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
 };
 use color_eyre::Result;
+use content::ContentScanner;
 use humansize::{make_format, BINARY};
-use mevi_common::{MemMap, MemState, MeviEvent, TraceeId, TraceePayload, TraceeSnapshot};
+use inspect::Inspector;
+use mevi_common::{
+    forked_memmap, AttrMap, Duplicates, MappingMap, MemMap, MemState, MeviEvent, NumaMap,
+    OutputChunk, Touches, TraceeId, TraceePayload, TraceeSnapshot, Usage, WorkingSet, ZeroFilled,
+    MAX_WARNINGS,
+};
+use numa::NumaLocator;
+use options::Options;
 use postage::{broadcast, sink::Sink, stream::Stream};
 use tokio::time::Instant;
 use tracer::Tracer;
-use tracing::debug;
+use tracing::{debug, info};
 use tracing_subscriber::EnvFilter;
+use usage::UsageSampler;
+use userfault::UffdLoop;
 
+mod console;
+mod content;
+mod inspect;
+mod numa;
+mod options;
+mod pagemap;
+mod remote;
+mod seccomp;
+mod syscall_info;
 mod tracer;
+mod usage;
 mod userfault;
 
 const SOCK_PATH: &str = "/tmp/mevi.sock";
@@ -32,19 +59,39 @@ async fn main() -> Result<()> {
         )
         .init();
 
+    let opts = Options::from_args()?;
+
     std::fs::remove_file(SOCK_PATH).ok();
     let listener = UnixListener::bind(SOCK_PATH).unwrap();
 
     let (tx, rx) = mpsc::sync_channel::<MeviEvent>(16);
     let tx2 = tx.clone();
+    let uffds = UffdLoop::spawn(tx.clone(), &opts)?;
+    let uffds2 = uffds.clone();
+    let inspector = Inspector::new(opts.inspect, uffds.clone());
+    let scan_contents = opts
+        .scan_contents
+        .map(|interval| (ContentScanner::spawn(tx.clone()), interval));
+    let numa = opts
+        .numa_interval
+        .map(|interval| (NumaLocator::spawn(tx.clone()), interval));
+    let usage = opts
+        .usage_interval
+        .map(|interval| (UsageSampler::spawn(tx.clone()), interval));
 
-    std::thread::spawn(move || Tracer::new(tx2, listener).unwrap().run().unwrap());
+    std::thread::spawn(move || {
+        Tracer::new(tx2, listener, uffds2, &opts)
+            .unwrap()
+            .run()
+            .unwrap()
+    });
 
     let (payload_tx, _) = broadcast::channel(16);
 
     let rs = RouterState {
         payload_tx: payload_tx.clone(),
         ev_tx: tx.clone(),
+        inspector,
     };
     let router = axum::Router::new()
         .route("/stream", axum::routing::get(stream))
@@ -52,7 +99,7 @@ async fn main() -> Result<()> {
     let addr = "127.0.0.1:5001".parse().unwrap();
     let server = axum::Server::bind(&addr).serve(router.into_make_service());
 
-    std::thread::spawn(move || relay(rx, payload_tx));
+    std::thread::spawn(move || relay(rx, payload_tx, uffds, scan_contents, numa, usage));
 
     server.await.unwrap();
     Ok(())
@@ -62,6 +109,20 @@ struct TraceeState {
     tid: TraceeId,
     cmdline: Vec<String>,
     map: MemMap,
+    degraded: Option<String>,
+    warnings: Vec<String>,
+    drift: Option<i64>,
+    last_resync: Option<Instant>,
+    touches: Touches,
+    touched_bytes: HashMap<TraceeId, u64>,
+    working_set: Option<WorkingSet>,
+    attrs: AttrMap,
+    parent: Option<TraceeId>,
+    usage: Option<Usage>,
+    zero_filled: Option<ZeroFilled>,
+    duplicates: Option<Duplicates>,
+    numa_nodes: Option<NumaMap>,
+    mappings: MappingMap,
     w_tx: broadcast::Sender<MeviEvent>,
 }
 
@@ -70,13 +131,150 @@ impl TraceeState {
         let ev = MeviEvent::TraceeEvent(self.tid, payload);
         _ = self.w_tx.blocking_send(ev);
     }
+
+    /// Resident ranges, and what kind of resident they are
+    fn resident(&self) -> impl Iterator<Item = (Range<u64>, MemState)> + '_ {
+        self.map
+            .iter()
+            .filter(|(_, state)| state.is_resident())
+            .map(|(range, state)| (range.clone(), *state))
+    }
+
+    /// Compares what we think is resident with what the kernel says, and
+    /// has the uffd loop resync from pagemap if we're too far off.
+    fn check_drift(&mut self, uffds: &UffdLoop) {
+        let Ok(status) = procfs::process::Process::new(self.tid.0 as _).and_then(|p| p.status())
+        else {
+            // it's exiting, we'll hear about it
+            return;
+        };
+        // that's everything mevi can track, in KiB
+        let actual = [status.rssanon, status.rssshmem, status.hugetlbpages]
+            .into_iter()
+            .flatten()
+            .sum::<u64>()
+            * 1024;
+        let computed: u64 = self
+            .map
+            .iter()
+            .filter(|(_, state)| state.is_resident())
+            .map(|(range, _)| range.end - range.start)
+            .sum();
+
+        let drift = computed as i64 - actual as i64;
+        if self.drift != Some(drift) {
+            self.drift = Some(drift);
+            self.send_ev(TraceePayload::Drift { bytes: drift });
+        }
+
+        let threshold = DRIFT_RESYNC_MIN.max(actual / DRIFT_RESYNC_RATIO);
+        let cooled_down = self
+            .last_resync
+            .is_none_or(|at| at.elapsed() >= RESYNC_COOLDOWN);
+        if drift.unsigned_abs() > threshold && cooled_down {
+            let formatter = make_format(BINARY);
+            info!(
+                "{} is off by {} ({} computed, {} actual), resyncing",
+                self.tid,
+                formatter(drift.unsigned_abs()),
+                formatter(computed),
+                formatter(actual),
+            );
+            self.last_resync = Some(Instant::now());
+            // not from here: the uffd loop reports corrections to us, and
+            // could block on a full channel.
+            let uffds = uffds.clone();
+            let tid = self.tid;
+            std::thread::spawn(move || uffds.reconcile_now(tid));
+        }
+    }
 }
 
-fn relay(ev_rx: mpsc::Receiver<MeviEvent>, mut payload_tx: broadcast::Sender<MeviEvent>) {
+/// How often we check for drift
+const DRIFT_INTERVAL: Duration = Duration::from_secs(1);
+/// We resync a tracee when we're off by more than this many bytes...
+const DRIFT_RESYNC_MIN: u64 = 16 * 1024 * 1024;
+/// ...or by more than 1/this of its actual resident set, whichever is bigger
+const DRIFT_RESYNC_RATIO: u64 = 8;
+/// Some drift can't be fixed by a resync (see README), don't retry too often
+const RESYNC_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// How many chunks of tracee output we replay to frontends that just connected
+const OUTPUT_BACKLOG: usize = 256;
+
+fn relay(
+    ev_rx: mpsc::Receiver<MeviEvent>,
+    mut payload_tx: broadcast::Sender<MeviEvent>,
+    uffds: UffdLoop,
+    scan_contents: Option<(ContentScanner, Duration)>,
+    numa: Option<(NumaLocator, Duration)>,
+    usage: Option<(UsageSampler, Duration)>,
+) {
     let mut tracees: HashMap<TraceeId, TraceeState> = Default::default();
+    let mut output_backlog: VecDeque<OutputChunk> = Default::default();
+    let mut next_drift_check = Instant::now() + DRIFT_INTERVAL;
+    let mut next_content_scan = scan_contents
+        .as_ref()
+        .map(|(_, interval)| Instant::now() + *interval);
+    let mut next_numa_check = numa
+        .as_ref()
+        .map(|(_, interval)| Instant::now() + *interval);
+    let mut next_usage_check = usage
+        .as_ref()
+        .map(|(_, interval)| Instant::now() + *interval);
 
     loop {
-        let ev = ev_rx.recv().unwrap();
+        if Instant::now() >= next_drift_check {
+            for tracee in tracees.values_mut() {
+                tracee.check_drift(&uffds);
+            }
+            next_drift_check = Instant::now() + DRIFT_INTERVAL;
+        }
+
+        if let (Some((scanner, interval)), Some(next)) = (&scan_contents, &mut next_content_scan) {
+            if Instant::now() >= *next {
+                let job = tracees
+                    .values()
+                    .map(|tracee| (tracee.tid, tracee.resident().map(|(r, _)| r).collect()))
+                    .collect();
+                scanner.request(job);
+                *next = Instant::now() + *interval;
+            }
+        }
+
+        if let (Some((locator, interval)), Some(next)) = (&numa, &mut next_numa_check) {
+            if Instant::now() >= *next {
+                let job = tracees
+                    .values()
+                    .map(|tracee| (tracee.tid, tracee.resident().map(|(r, _)| r).collect()))
+                    .collect();
+                locator.request(job);
+                *next = Instant::now() + *interval;
+            }
+        }
+
+        if let (Some((sampler, interval)), Some(next)) = (&usage, &mut next_usage_check) {
+            if Instant::now() >= *next {
+                sampler.request(tracees.keys().copied().collect());
+                *next = Instant::now() + *interval;
+            }
+        }
+
+        let deadline = [
+            Some(next_drift_check),
+            next_content_scan,
+            next_numa_check,
+            next_usage_check,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap();
+        let ev = match ev_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ev) => ev,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(e) => panic!("{e:?}"),
+        };
         debug!("{:?}", ev);
 
         let (tid, payload) = match ev {
@@ -86,22 +284,75 @@ fn relay(ev_rx: mpsc::Receiver<MeviEvent>, mut payload_tx: broadcast::Sender<Mev
                         tid: tracee.tid,
                         cmdline: tracee.cmdline.clone(),
                         map: tracee.map.clone(),
+                        degraded: tracee.degraded.clone(),
+                        warnings: tracee.warnings.clone(),
+                        drift: tracee.drift,
+                        touches: tracee.touches.clone(),
+                        touched_bytes: tracee.touched_bytes.clone(),
+                        working_set: tracee.working_set.clone(),
+                        attrs: tracee.attrs.clone(),
+                        parent: tracee.parent,
+                        usage: tracee.usage,
+                        zero_filled: tracee.zero_filled.clone(),
+                        duplicates: tracee.duplicates.clone(),
+                        numa_nodes: tracee.numa_nodes.clone(),
+                        mappings: tracee.mappings.clone(),
                     });
                 }
                 _ = payload_tx.blocking_send(MeviEvent::Snapshot(snap_tracees));
+                // frontends clear their console on snapshot, so everyone gets
+                // the backlog again
+                for chunk in &output_backlog {
+                    _ = payload_tx.blocking_send(MeviEvent::Output(chunk.clone()));
+                }
+                continue;
+            }
+            MeviEvent::Output(chunk) => {
+                if output_backlog.len() == OUTPUT_BACKLOG {
+                    output_backlog.pop_front();
+                }
+                output_backlog.push_back(chunk.clone());
+                _ = payload_tx.blocking_send(MeviEvent::Output(chunk));
                 continue;
             }
             MeviEvent::TraceeEvent(tid, ev) => (tid, ev),
+            MeviEvent::Inspection(_) => {
+                // those go straight to the frontend that asked
+                continue;
+            }
+        };
+
+        // the parent might not be around anymore by the time it's needed
+        let inherited = match &payload {
+            TraceePayload::Forked { parent } => tracees.get(parent).map(|p| forked_memmap(&p.map)),
+            _ => None,
         };
 
         let tracee = tracees.entry(tid).or_insert_with(|| TraceeState {
             tid,
             cmdline: Default::default(),
             map: Default::default(),
+            degraded: Default::default(),
+            warnings: Default::default(),
+            drift: Default::default(),
+            last_resync: Default::default(),
+            touches: Default::default(),
+            touched_bytes: Default::default(),
+            working_set: Default::default(),
+            attrs: Default::default(),
+            parent: Default::default(),
+            usage: Default::default(),
+            zero_filled: Default::default(),
+            duplicates: Default::default(),
+            numa_nodes: Default::default(),
+            mappings: Default::default(),
             w_tx: payload_tx.clone(),
         });
 
         payload.apply_to_memmap(&mut tracee.map);
+        payload.apply_to_touches(&mut tracee.touches);
+        payload.apply_to_attrs(&mut tracee.attrs);
+        payload.apply_to_mappings(&mut tracee.mappings);
         tracee.send_ev(payload.clone());
 
         match payload {
@@ -112,7 +363,7 @@ fn relay(ev_rx: mpsc::Receiver<MeviEvent>, mut payload_tx: broadcast::Sender<Mev
                     for (range, state) in tracee.map.iter() {
                         let size = range.end - range.start;
                         total_vsz += size;
-                        if let MemState::Resident = state {
+                        if state.is_resident() {
                             total_rss += size;
                         }
                     }
@@ -123,13 +374,70 @@ fn relay(ev_rx: mpsc::Receiver<MeviEvent>, mut payload_tx: broadcast::Sender<Mev
                         formatter(total_rss),
                         tracee.cmdline,
                     );
+                    if let Some(duplicates) = &tracee.duplicates {
+                        let duplicated: u64 =
+                            duplicates.pages.iter().map(|r| r.end - r.start).sum();
+                        let with = duplicates
+                            .with
+                            .iter()
+                            .map(|(other, bytes)| format!("{} with {other}", formatter(*bytes)))
+                            .collect::<Vec<_>>();
+                        tracing::warn!(
+                            "{tid} had {} of duplicate pages ({} mergeable, {} within itself, {})",
+                            formatter(duplicated),
+                            formatter(duplicates.mergeable),
+                            formatter(duplicates.within),
+                            if with.is_empty() {
+                                "none with other processes".to_string()
+                            } else {
+                                with.join(", ")
+                            },
+                        );
+                    }
                 }
 
                 tracees.remove(&tid);
             }
+            TraceePayload::PageFault {
+                range,
+                thread: Some(thread),
+                ..
+            } => {
+                *tracee.touched_bytes.entry(thread).or_default() += range.end - range.start;
+            }
             TraceePayload::CmdLineChange { cmdline } => {
                 tracee.cmdline = cmdline;
             }
+            TraceePayload::WorkingSet(working_set) => {
+                tracee.working_set = Some(working_set);
+            }
+            TraceePayload::ZeroFilled(zero_filled) => {
+                tracee.zero_filled = Some(zero_filled);
+            }
+            TraceePayload::Duplicates(duplicates) => {
+                tracee.duplicates = Some(duplicates);
+            }
+            TraceePayload::NumaNodes(nodes) => {
+                tracee.numa_nodes = Some(nodes);
+            }
+            TraceePayload::Usage(usage) => {
+                tracee.usage = Some(usage);
+            }
+            TraceePayload::Forked { parent } => {
+                tracee.parent = Some(parent);
+                if let Some(map) = inherited {
+                    tracee.map = map;
+                }
+            }
+            TraceePayload::Degraded { reason } => {
+                tracee.degraded = Some(reason);
+            }
+            TraceePayload::Warning { message } => {
+                if tracee.warnings.len() == MAX_WARNINGS {
+                    tracee.warnings.remove(0);
+                }
+                tracee.warnings.push(message);
+            }
             _ => {
                 // ignore
             }
@@ -141,14 +449,32 @@ fn relay(ev_rx: mpsc::Receiver<MeviEvent>, mut payload_tx: broadcast::Sender<Mev
 struct RouterState {
     payload_tx: broadcast::Sender<MeviEvent>,
     ev_tx: mpsc::SyncSender<MeviEvent>,
+    inspector: Inspector,
 }
 
-async fn stream(State(rs): State<RouterState>, upgrade: WebSocketUpgrade) -> impl IntoResponse {
-    upgrade.on_upgrade(move |ws| {
-        let payload_rx = rs.payload_tx.subscribe();
-        _ = rs.ev_tx.send(MeviEvent::Snapshot(vec![]));
-        handle_ws(payload_rx, ws)
-    })
+/// Where the frontend is served from, by `trunk serve`
+const FRONTEND_ORIGINS: &[&str] = &["http://localhost:8080", "http://127.0.0.1:8080"];
+
+async fn stream(
+    State(rs): State<RouterState>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // websockets aren't subject to the same-origin policy: without this, any
+    // page open in the browser could read tracees' memory
+    let origin = headers.get(header::ORIGIN).and_then(|o| o.to_str().ok());
+    let from_frontend = origin.is_some_and(|origin| FRONTEND_ORIGINS.contains(&origin));
+    if !from_frontend {
+        info!("websocket connection from origin {origin:?} can't inspect memory");
+    }
+
+    upgrade
+        .on_upgrade(move |ws| {
+            let payload_rx = rs.payload_tx.subscribe();
+            _ = rs.ev_tx.send(MeviEvent::Snapshot(vec![]));
+            handle_ws(payload_rx, ws, rs.inspector, from_frontend)
+        })
+        .into_response()
 }
 
 lazy_static::lazy_static! {
@@ -157,18 +483,47 @@ lazy_static::lazy_static! {
     );
 }
 
-async fn handle_ws(mut payload_rx: broadcast::Receiver<MeviEvent>, mut ws: WebSocket) {
+async fn handle_ws(
+    mut payload_rx: broadcast::Receiver<MeviEvent>,
+    mut ws: WebSocket,
+    inspector: Inspector,
+    from_frontend: bool,
+) {
     let interval = *MEVI_INTERVAL;
     let mut next_flush = Instant::now() + interval;
     let mut queue = vec![];
+    // inspecting may fault pages in, which needs the uffd loop, which needs
+    // the relay, which needs us to keep draining `payload_rx` meanwhile.
+    let (inspection_tx, mut inspection_rx) = tokio::sync::mpsc::channel(1);
 
     loop {
-        match tokio::time::timeout_at(next_flush, payload_rx.recv()).await {
-            Ok(ev) => {
+        tokio::select! {
+            ev = payload_rx.recv() => {
                 let ev = ev.unwrap();
                 queue.push(ev);
             }
-            Err(_elapsed) => {
+            Some(inspection) = inspection_rx.recv() => {
+                queue.push(MeviEvent::Inspection(inspection));
+            }
+            msg = ws.recv() => {
+                let Some(Ok(msg)) = msg else {
+                    // the frontend went away
+                    return;
+                };
+                if let Message::Binary(data) = msg {
+                    match mevi_common::deserialize_request(&data) {
+                        Ok(req) => {
+                            let inspector = inspector.clone();
+                            let inspection_tx = inspection_tx.clone();
+                            tokio::spawn(async move {
+                                _ = inspection_tx.send(inspector.inspect(req, from_frontend).await).await;
+                            });
+                        }
+                        Err(e) => debug!("bad request from frontend: {e}"),
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(next_flush) => {
                 if !queue.is_empty() {
                     ws.send(Message::Binary(
                         mevi_common::serialize_many(&queue[..]).unwrap(),
@@ -0,0 +1,102 @@
+use std::{collections::HashMap, ops::Range, sync::mpsc};
+
+use mevi_common::{MeviEvent, NumaMap, TraceeId, TraceePayload};
+use nix::errno::Errno;
+use tracing::{debug, warn};
+
+/// How many pages we ask about in one `move_pages` call
+const BATCH_SIZE: usize = 4096;
+
+/// Resident ranges of every tracee we want located
+type Job = Vec<(TraceeId, Vec<Range<u64>>)>;
+
+/// Finds out which NUMA node tracees' resident pages are on.
+///
+/// `move_pages` doesn't fault anything in when it's not given nodes, but it
+/// still walks page tables one page at a time, so it's kept off the relay
+/// thread.
+#[derive(Clone)]
+pub(crate) struct NumaLocator {
+    jobs: mpsc::SyncSender<Job>,
+}
+
+impl NumaLocator {
+    pub(crate) fn spawn(tx: mpsc::SyncSender<MeviEvent>) -> Self {
+        let (jobs, jobs_rx) = mpsc::sync_channel::<Job>(1);
+        std::thread::spawn(move || {
+            let mut last: HashMap<TraceeId, NumaMap> = Default::default();
+            for job in jobs_rx {
+                last.retain(|tid, _| job.iter().any(|(t, _)| t == tid));
+                for (tid, ranges) in job {
+                    let nodes = match locate(tid, &ranges) {
+                        Ok(nodes) => nodes,
+                        Err(Errno::ESRCH) => continue,
+                        Err(e @ (Errno::ENOSYS | Errno::EPERM)) => {
+                            warn!("can't tell which NUMA node memory is on ({e}), giving up");
+                            return;
+                        }
+                        Err(e) => {
+                            debug!("{tid} move_pages failed: {e}");
+                            continue;
+                        }
+                    };
+                    if last.get(&tid) == Some(&nodes) {
+                        continue;
+                    }
+                    last.insert(tid, nodes.clone());
+                    let payload = TraceePayload::NumaNodes(nodes);
+                    if tx.send(MeviEvent::TraceeEvent(tid, payload)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Has resident memory located, unless the previous job is still going.
+    pub(crate) fn request(&self, job: Job) {
+        if self.jobs.try_send(job).is_err() {
+            debug!("NUMA locator still running, skipping this one");
+        }
+    }
+}
+
+/// Asks the kernel which node each page of `ranges` is on, with `move_pages`
+/// and no target nodes.
+fn locate(tid: TraceeId, ranges: &[Range<u64>]) -> nix::Result<NumaMap> {
+    let page_size = procfs::page_size();
+    let mut pages = ranges
+        .iter()
+        .flat_map(|range| (range.start..range.end).step_by(page_size as _));
+
+    let mut nodes = NumaMap::new();
+    let mut batch: Vec<u64> = Vec::with_capacity(BATCH_SIZE);
+    let mut status = vec![0i32; BATCH_SIZE];
+    loop {
+        batch.clear();
+        batch.extend(pages.by_ref().take(BATCH_SIZE));
+        if batch.is_empty() {
+            break;
+        }
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_move_pages,
+                tid.0 as libc::pid_t,
+                batch.len() as libc::c_ulong,
+                batch.as_ptr(),
+                std::ptr::null::<libc::c_int>(),
+                status.as_mut_ptr(),
+                0 as libc::c_int,
+            )
+        };
+        Errno::result(ret)?;
+        for (&addr, &node) in batch.iter().zip(&status) {
+            // negative means it's not there (anymore), or not a normal page
+            if node >= 0 {
+                nodes.insert(addr..addr + page_size, node as u32);
+            }
+        }
+    }
+    Ok(nodes)
+}
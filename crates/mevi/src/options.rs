@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+
+use crate::console::StdioMode;
+
+const USAGE: &str =
+    "usage: mevi [--pty | --pipes] [--trace-all-syscalls] [--bench] [--user-faults-only] [--fault-around PAGES] [--track-writes] [--reconcile-interval MS] [--working-set SECS] [--scan-contents SECS] [--numa SECS] [--usage SECS] [--inspect] PROGRAM [ARGS...]";
+
+/// mevi's own flags, which come before the traced program's name.
+#[derive(Clone)]
+pub(crate) struct Options {
+    pub(crate) stdio: StdioMode,
+    /// Only stop tracees on memory-related syscalls, via a seccomp filter,
+    /// instead of stepping through every syscall.
+    pub(crate) seccomp: bool,
+    /// Report tracing overhead when the program exits
+    pub(crate) bench: bool,
+    /// Create userfaultfds that only see faults from userland, which doesn't
+    /// need `vm.unprivileged_userfaultfd`. Kernel accesses to registered
+    /// memory fail with EFAULT instead.
+    pub(crate) user_faults_only: bool,
+    /// How many pages to fill in on each page fault, starting with the one
+    /// that faulted. More pages means fewer round trips through mevi, but
+    /// the extra ones only show up as "prefaulted".
+    pub(crate) fault_around: u64,
+    /// Tell pages that were only read (and are still the zero page) apart
+    /// from pages that were written to, with userfaultfd write-protection.
+    pub(crate) track_writes: bool,
+    /// How often to check tracked memory against `/proc/pid/pagemap`, to
+    /// catch what doesn't go through page faults, like swapping. `None` if
+    /// it's disabled, which is the default: on big programs, that's a lot of
+    /// pagemap to read.
+    pub(crate) reconcile_interval: Option<Duration>,
+    /// Estimate the working set, as the pages written to within that long,
+    /// from soft-dirty bits.
+    pub(crate) working_set_window: Option<Duration>,
+    /// How often to read through tracees' resident memory, to find pages
+    /// that are all zeros or duplicated. `None` if it's disabled.
+    pub(crate) scan_contents: Option<Duration>,
+    /// How often to check which NUMA node resident pages are on. `None` if
+    /// it's disabled.
+    pub(crate) numa_interval: Option<Duration>,
+    /// How often to read RSS, PSS and USS from `/proc/PID/smaps_rollup`.
+    /// `None` if it's disabled.
+    pub(crate) usage_interval: Option<Duration>,
+    /// Let frontends read the contents of tracees' pages. Off unless asked
+    /// for, since that can be anything.
+    pub(crate) inspect: bool,
+    pub(crate) command: Vec<String>,
+}
+
+impl Options {
+    pub(crate) fn from_args() -> Result<Self> {
+        let mut args = std::env::args();
+        // skip our own name
+        args.next().unwrap();
+
+        let mut opts = Self {
+            stdio: StdioMode::Inherit,
+            seccomp: true,
+            bench: false,
+            user_faults_only: false,
+            fault_around: 1,
+            track_writes: false,
+            reconcile_interval: None,
+            working_set_window: None,
+            scan_contents: None,
+            numa_interval: None,
+            usage_interval: None,
+            inspect: false,
+            command: vec![],
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--pty" => opts.stdio = StdioMode::Pty,
+                "--pipes" => opts.stdio = StdioMode::Pipes,
+                "--trace-all-syscalls" => opts.seccomp = false,
+                "--bench" => opts.bench = true,
+                "--user-faults-only" => opts.user_faults_only = true,
+                "--track-writes" => opts.track_writes = true,
+                "--inspect" => opts.inspect = true,
+                "--fault-around" => {
+                    opts.fault_around = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| {
+                            eyre!("--fault-around takes a number of pages (1 or more)\n{USAGE}")
+                        })?;
+                }
+                "--reconcile-interval" => {
+                    let ms: u64 = args.next().and_then(|n| n.parse().ok()).ok_or_else(|| {
+                        eyre!("--reconcile-interval takes milliseconds (0 to disable)\n{USAGE}")
+                    })?;
+                    opts.reconcile_interval = (ms > 0).then(|| Duration::from_millis(ms));
+                }
+                "--working-set" => {
+                    let secs: u64 = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| {
+                            eyre!("--working-set takes a number of seconds (1 or more)\n{USAGE}")
+                        })?;
+                    opts.working_set_window = Some(Duration::from_secs(secs));
+                }
+                "--scan-contents" => {
+                    let secs: u64 = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| {
+                            eyre!("--scan-contents takes a number of seconds (1 or more)\n{USAGE}")
+                        })?;
+                    opts.scan_contents = Some(Duration::from_secs(secs));
+                }
+                "--numa" => {
+                    let secs: u64 = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| {
+                            eyre!("--numa takes a number of seconds (1 or more)\n{USAGE}")
+                        })?;
+                    opts.numa_interval = Some(Duration::from_secs(secs));
+                }
+                "--usage" => {
+                    let secs: u64 = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| {
+                            eyre!("--usage takes a number of seconds (1 or more)\n{USAGE}")
+                        })?;
+                    opts.usage_interval = Some(Duration::from_secs(secs));
+                }
+                "--" => break,
+                flag if flag.starts_with("--") => {
+                    return Err(eyre!("unknown flag {flag:?}\n{USAGE}"));
+                }
+                _ => {
+                    opts.command.push(arg);
+                    break;
+                }
+            }
+        }
+        opts.command.extend(args);
+
+        if opts.command.is_empty() {
+            return Err(eyre!("no program to trace\n{USAGE}"));
+        }
+        Ok(opts)
+    }
+}
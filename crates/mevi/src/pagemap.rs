@@ -0,0 +1,254 @@
+use std::ops::Range;
+
+use mevi_common::MemState;
+use nix::unistd::{sysconf, SysconfVar};
+use procfs::{
+    process::{MemoryPageFlags, PageInfo, Pfn, Process, SwapPageFlags},
+    KPageFlags, PhysicalPageFlags, ProcResult,
+};
+use tracing::debug;
+
+use crate::userfault::Backing;
+
+/// Reads which pages are resident from `/proc/pid/pagemap`, and which of
+/// those are huge pages.
+pub(crate) struct Scanner {
+    page_size: u64,
+    /// Size of a PMD-mapped transparent huge page, 2MiB on x86_64
+    thp_size: u64,
+    /// Only readable with CAP_SYS_ADMIN, which is also what it takes for
+    /// pagemap to show PFNs.
+    kpageflags: Option<KPageFlags>,
+}
+
+impl Scanner {
+    pub(crate) fn new() -> Self {
+        Self {
+            page_size: sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as u64,
+            thp_size: std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .unwrap_or(2 * 1024 * 1024),
+            kpageflags: KPageFlags::new().ok(),
+        }
+    }
+
+    /// Whether [Scanner::scan] tells exactly which pages are THPs, rather
+    /// than guessing from a hint.
+    pub(crate) fn sees_thps(&self) -> bool {
+        self.kpageflags.is_some()
+    }
+
+    /// The state of every page in `range`, coalesced into runs of the same
+    /// state.
+    ///
+    /// Pages of hugetlb mappings are always huge. For everything else, each
+    /// aligned, fully-present THP-sized block is checked against
+    /// `/proc/kpageflags`. Without it, there's no telling where THPs are,
+    /// so none of them are reported as huge.
+    ///
+    /// Private anonymous pages that aren't exclusively mapped are either the
+    /// shared zero page, which is what prefaulted pages and pages that were
+    /// only read are backed by, or shared copy-on-write with a process that
+    /// `forked` from (or into) this one. `/proc/kpageflags` tells which, and
+    /// without it, they're guessed to be shared if the process forked.
+    pub(crate) fn scan(
+        &mut self,
+        p: &Process,
+        range: Range<u64>,
+        backing: Backing,
+        forked: bool,
+    ) -> ProcResult<Vec<(Range<u64>, MemState)>> {
+        let page_size = self.page_size;
+        let start_idx = (range.start / page_size) as usize;
+        let end_idx = (range.end / page_size) as usize;
+
+        let mut pages: Vec<(MemState, Option<Pfn>)> = vec![];
+        for pi in p.pagemap()?.get_range_info(start_idx..end_idx)? {
+            let page = match pi {
+                PageInfo::SwapPage(_) => (MemState::Swapped, None),
+                PageInfo::MemoryPage(mp) if mp.contains(MemoryPageFlags::PRESENT) => {
+                    let pfn = mp.get_page_frame_number();
+                    // PFNs read as zero without CAP_SYS_ADMIN
+                    let pfn = (pfn.0 != 0).then_some(pfn);
+                    let exclusive = backing != Backing::Anon
+                        || mp.intersects(MemoryPageFlags::MMAP_EXCLUSIVE | MemoryPageFlags::FILE);
+                    let zero_page = !exclusive && self.is_zero_page(pfn).unwrap_or(!forked);
+                    match (exclusive, zero_page) {
+                        (true, _) => (MemState::Resident, pfn),
+                        (false, true) => (MemState::ZeroPage, pfn),
+                        (false, false) => (MemState::SharedCow, pfn),
+                    }
+                }
+                PageInfo::MemoryPage(_) => (MemState::NotResident, None),
+            };
+            pages.push(page);
+        }
+
+        if let Backing::Hugetlb { .. } = backing {
+            for (state, _) in &mut pages {
+                if *state == MemState::Resident {
+                    *state = MemState::HugeResident;
+                }
+            }
+        } else if let Some(kpf) = &mut self.kpageflags {
+            let block_pages = (self.thp_size / page_size) as usize;
+
+            // index of the first page that starts an aligned block
+            let first = ((block_pages - start_idx % block_pages) % block_pages).min(pages.len());
+            for block in pages[first..].chunks_exact_mut(block_pages) {
+                if block.iter().any(|(state, _)| *state != MemState::Resident) {
+                    continue;
+                }
+                let huge = match block[0].1 {
+                    Some(pfn) => kpf
+                        .get_info(pfn)
+                        .map(|flags| flags.contains(PhysicalPageFlags::THP))
+                        .unwrap_or(false),
+                    None => false,
+                };
+                if huge {
+                    for (state, _) in block {
+                        *state = MemState::HugeResident;
+                    }
+                }
+            }
+        }
+
+        let mut runs: Vec<(Range<u64>, MemState)> = vec![];
+        for (i, (state, _)) in pages.into_iter().enumerate() {
+            let addr = range.start + i as u64 * page_size;
+            match runs.last_mut() {
+                Some((run, run_state)) if *run_state == state => run.end = addr + page_size,
+                _ => runs.push((addr..addr + page_size, state)),
+            }
+        }
+        debug!("{range:x?} is {} runs", runs.len());
+        Ok(runs)
+    }
+
+    /// The runs of pages in `range` that are in memory right now, and can be
+    /// read without faulting anything in.
+    pub(crate) fn present(
+        &mut self,
+        p: &Process,
+        range: Range<u64>,
+    ) -> ProcResult<Vec<Range<u64>>> {
+        let page_size = self.page_size;
+        let start_idx = (range.start / page_size) as usize;
+        let end_idx = (range.end / page_size) as usize;
+
+        let mut runs: Vec<Range<u64>> = vec![];
+        for (i, pi) in p
+            .pagemap()?
+            .get_range_info(start_idx..end_idx)?
+            .into_iter()
+            .enumerate()
+        {
+            let present = match pi {
+                PageInfo::MemoryPage(mp) => mp.contains(MemoryPageFlags::PRESENT),
+                PageInfo::SwapPage(_) => false,
+            };
+            if !present {
+                continue;
+            }
+            let addr = range.start + i as u64 * page_size;
+            match runs.last_mut() {
+                Some(run) if run.end == addr => run.end = addr + page_size,
+                _ => runs.push(addr..addr + page_size),
+            }
+        }
+        Ok(runs)
+    }
+
+    /// The runs of pages in `range` that are in memory right now, and have
+    /// memory of their own: the shared zero page doesn't. Without
+    /// `/proc/kpageflags` to tell which pages are the zero page, private
+    /// anonymous pages that aren't exclusively mapped are left out too.
+    ///
+    /// Each run says whether its pages are exclusive to this process, rather
+    /// than shared copy-on-write with another one.
+    pub(crate) fn backed(
+        &mut self,
+        p: &Process,
+        range: Range<u64>,
+    ) -> ProcResult<Vec<(Range<u64>, bool)>> {
+        let page_size = self.page_size;
+        let start_idx = (range.start / page_size) as usize;
+        let end_idx = (range.end / page_size) as usize;
+
+        let mut runs: Vec<(Range<u64>, bool)> = vec![];
+        for (i, pi) in p
+            .pagemap()?
+            .get_range_info(start_idx..end_idx)?
+            .into_iter()
+            .enumerate()
+        {
+            let exclusive = match pi {
+                PageInfo::MemoryPage(mp) if mp.contains(MemoryPageFlags::PRESENT) => {
+                    let pfn = mp.get_page_frame_number();
+                    if mp.intersects(MemoryPageFlags::MMAP_EXCLUSIVE | MemoryPageFlags::FILE) {
+                        true
+                    } else if self.is_zero_page((pfn.0 != 0).then_some(pfn)) == Some(false) {
+                        false
+                    } else {
+                        continue;
+                    }
+                }
+                _ => continue,
+            };
+            let addr = range.start + i as u64 * page_size;
+            match runs.last_mut() {
+                Some((run, run_exclusive)) if run.end == addr && *run_exclusive == exclusive => {
+                    run.end = addr + page_size
+                }
+                _ => runs.push((addr..addr + page_size, exclusive)),
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Whether the page at `pfn` is the shared zero page, if
+    /// `/proc/kpageflags` can tell.
+    fn is_zero_page(&mut self, pfn: Option<Pfn>) -> Option<bool> {
+        let (Some(kpf), Some(pfn)) = (&mut self.kpageflags, pfn) else {
+            return None;
+        };
+        let flags = kpf.get_info(pfn).ok()?;
+        Some(flags.contains(PhysicalPageFlags::ZERO_PAGE))
+    }
+
+    /// The runs of pages in `range` that were written to since soft-dirty
+    /// bits were last cleared (or that are new since then).
+    pub(crate) fn soft_dirty(
+        &mut self,
+        p: &Process,
+        range: Range<u64>,
+    ) -> ProcResult<Vec<Range<u64>>> {
+        let page_size = self.page_size;
+        let start_idx = (range.start / page_size) as usize;
+        let end_idx = (range.end / page_size) as usize;
+
+        let mut runs: Vec<Range<u64>> = vec![];
+        for (i, pi) in p
+            .pagemap()?
+            .get_range_info(start_idx..end_idx)?
+            .into_iter()
+            .enumerate()
+        {
+            let dirty = match pi {
+                PageInfo::MemoryPage(mp) => mp.contains(MemoryPageFlags::SOFT_DIRTY),
+                PageInfo::SwapPage(sp) => sp.contains(SwapPageFlags::SOFT_DIRTY),
+            };
+            if !dirty {
+                continue;
+            }
+            let addr = range.start + i as u64 * page_size;
+            match runs.last_mut() {
+                Some(run) if run.end == addr => run.end = addr + page_size,
+                _ => runs.push(addr..addr + page_size),
+            }
+        }
+        Ok(runs)
+    }
+}
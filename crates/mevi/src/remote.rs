@@ -0,0 +1,128 @@
+use std::{
+    fs::OpenOptions,
+    os::unix::fs::FileExt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use nix::{errno::Errno, sys::ptrace, unistd::Pid};
+use tracing::debug;
+
+const WORD_SIZE: usize = std::mem::size_of::<usize>();
+
+// once process_vm_{readv,writev} fails for a reason that isn't specific to a
+// tracee (seccomp'd mevi, old kernel...), don't bother trying it again.
+static VM_RW_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// The raw bytes of a plain-old-data struct, to copy it into a tracee.
+pub(crate) fn as_bytes<T: Copy>(v: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+/// The raw bytes of a plain-old-data struct, to copy it from a tracee.
+pub(crate) fn as_bytes_mut<T: Copy>(v: &mut T) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(v as *mut T as *mut u8, std::mem::size_of::<T>()) }
+}
+
+/// Copies `v` at the start of `dst`, for laying out several structs in a
+/// buffer that's then written to a tracee in one go.
+pub(crate) fn put<T: Copy>(dst: &mut [u8], v: &T) {
+    let src = as_bytes(v);
+    dst[..src.len()].copy_from_slice(src);
+}
+
+/// Writes `data` to `addr` in the address space of `pid`.
+///
+/// Tries `process_vm_writev`, then `/proc/pid/mem`, then falls back to
+/// poking one word at a time with ptrace, which costs a syscall per word.
+pub(crate) fn write(pid: Pid, addr: usize, data: &[u8]) -> nix::Result<()> {
+    if !VM_RW_UNAVAILABLE.load(Ordering::Relaxed) {
+        let local = libc::iovec {
+            iov_base: data.as_ptr() as _,
+            iov_len: data.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as _,
+            iov_len: data.len(),
+        };
+        let ret = unsafe { libc::process_vm_writev(pid.as_raw(), &local, 1, &remote, 1, 0) };
+        match Errno::result(ret) {
+            Ok(n) if n as usize == data.len() => return Ok(()),
+            Ok(n) => debug!("{pid} process_vm_writev was short ({n} bytes)"),
+            Err(e) => on_vm_rw_error("process_vm_writev", pid, e)?,
+        }
+    }
+
+    match OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/{pid}/mem"))
+        .and_then(|f| f.write_all_at(data, addr as _))
+    {
+        Ok(()) => return Ok(()),
+        Err(e) => debug!("{pid} writing to /proc/{pid}/mem failed ({e}), poking words"),
+    }
+
+    for (i, chunk) in data.chunks(WORD_SIZE).enumerate() {
+        let word_addr = addr + i * WORD_SIZE;
+        let mut word = [0u8; WORD_SIZE];
+        if chunk.len() < WORD_SIZE {
+            // don't clobber whatever comes after `data`
+            word = (ptrace::read(pid, word_addr as _)? as usize).to_ne_bytes();
+        }
+        word[..chunk.len()].copy_from_slice(chunk);
+        unsafe { ptrace::write(pid, word_addr as _, usize::from_ne_bytes(word) as _)? };
+    }
+    Ok(())
+}
+
+/// Reads `data.len()` bytes at `addr` from the address space of `pid`, with
+/// the same fallbacks as [write].
+pub(crate) fn read(pid: Pid, addr: usize, data: &mut [u8]) -> nix::Result<()> {
+    if !VM_RW_UNAVAILABLE.load(Ordering::Relaxed) {
+        let local = libc::iovec {
+            iov_base: data.as_mut_ptr() as _,
+            iov_len: data.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as _,
+            iov_len: data.len(),
+        };
+        let ret = unsafe { libc::process_vm_readv(pid.as_raw(), &local, 1, &remote, 1, 0) };
+        match Errno::result(ret) {
+            Ok(n) if n as usize == data.len() => return Ok(()),
+            Ok(n) => debug!("{pid} process_vm_readv was short ({n} bytes)"),
+            Err(e) => on_vm_rw_error("process_vm_readv", pid, e)?,
+        }
+    }
+
+    match OpenOptions::new()
+        .read(true)
+        .open(format!("/proc/{pid}/mem"))
+        .and_then(|f| f.read_exact_at(data, addr as _))
+    {
+        Ok(()) => return Ok(()),
+        Err(e) => debug!("{pid} reading /proc/{pid}/mem failed ({e}), peeking words"),
+    }
+
+    for (i, chunk) in data.chunks_mut(WORD_SIZE).enumerate() {
+        let word = ptrace::read(pid, (addr + i * WORD_SIZE) as _)? as usize;
+        chunk.copy_from_slice(&word.to_ne_bytes()[..chunk.len()]);
+    }
+    Ok(())
+}
+
+/// ESRCH means the tracee is gone, which the fallbacks won't fix. Anything
+/// else might be specific to the syscall, so the fallbacks get a chance.
+fn on_vm_rw_error(what: &str, pid: Pid, e: Errno) -> nix::Result<()> {
+    match e {
+        Errno::ESRCH => Err(e),
+        Errno::ENOSYS | Errno::EPERM => {
+            debug!("{pid} {what} is unavailable ({e}), not trying it again");
+            VM_RW_UNAVAILABLE.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        _ => {
+            debug!("{pid} {what} failed ({e}), falling back");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{os::unix::process::CommandExt, process::Command};
+
+use libc::{sock_filter, sock_fprog};
+
+use crate::syscall_info::AUDIT_ARCH_X86_64;
+
+/// The syscalls the tracer needs to see. Everything else runs at full speed,
+/// without stopping the tracee.
+const TRACED_SYSCALLS: &[i64] = &[
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_mlock,
+    libc::SYS_mlock2,
+    libc::SYS_munlock,
+    libc::SYS_mlockall,
+    libc::SYS_munlockall,
+    libc::SYS_process_madvise,
+    libc::SYS_process_mrelease,
+    libc::SYS_brk,
+    libc::SYS_mprotect,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_fork,
+    libc::SYS_vfork,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+// offsets into `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as _,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as _,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Builds a filter that returns SECCOMP_RET_TRACE for [TRACED_SYSCALLS] and
+/// SECCOMP_RET_ALLOW for everything else, including other architectures.
+fn build_filter() -> Vec<sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let mut filter = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH_X86_64, 1, 0),
+        stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+        stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+    ];
+    let n = TRACED_SYSCALLS.len();
+    for (i, nr) in TRACED_SYSCALLS.iter().enumerate() {
+        // on match, skip the remaining comparisons and the "allow"
+        let to_trace = (n - i) as u8;
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, to_trace, 0));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_TRACE));
+    filter
+}
+
+/// Makes `cmd` install the syscall filter right before exec'ing. The filter
+/// is inherited by every child, so it only needs to happen once.
+///
+/// The tracer must set `PTRACE_O_TRACESECCOMP`, otherwise traced syscalls
+/// fail with ENOSYS. That includes the `execve` that starts the program, so
+/// the child stops itself with SIGSTOP once the filter is in place, and the
+/// tracer gets to set its options before resuming it.
+pub(crate) fn install_on_exec(cmd: &mut Command) {
+    // allocating after fork isn't safe, build the program beforehand
+    let mut filter = build_filter();
+
+    unsafe {
+        cmd.pre_exec(move || {
+            let prog = sock_fprog {
+                len: filter.len() as _,
+                filter: filter.as_mut_ptr(),
+            };
+            // required to install a filter without CAP_SYS_ADMIN
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const sock_fprog,
+            ) < 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            libc::raise(libc::SIGSTOP);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+
+    /// Runs `filter` the way the kernel would, on a syscall `nr` made with
+    /// `arch`, for the subset of BPF that [build_filter] uses.
+    fn run(filter: &[sock_filter], arch: u32, nr: u32) -> u32 {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = filter[pc];
+            let code = insn.code as u32;
+            pc += 1;
+            if code == BPF_LD | BPF_W | BPF_ABS {
+                acc = match insn.k {
+                    0 => nr,
+                    4 => arch,
+                    k => panic!("unexpected load at offset {k}"),
+                };
+            } else if code == BPF_JMP | BPF_JEQ | BPF_K {
+                pc += if acc == insn.k { insn.jt } else { insn.jf } as usize;
+            } else if code == BPF_RET | BPF_K {
+                return insn.k;
+            } else {
+                panic!("unexpected instruction {code:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn traces_only_traced_syscalls() {
+        let filter = build_filter();
+        for nr in TRACED_SYSCALLS {
+            assert_eq!(
+                run(&filter, AUDIT_ARCH_X86_64, *nr as u32),
+                libc::SECCOMP_RET_TRACE,
+                "syscall {nr}"
+            );
+        }
+        for nr in [libc::SYS_read, libc::SYS_write, libc::SYS_getpid] {
+            assert_eq!(
+                run(&filter, AUDIT_ARCH_X86_64, nr as u32),
+                libc::SECCOMP_RET_ALLOW,
+                "syscall {nr}"
+            );
+        }
+    }
+
+    #[test]
+    fn allows_other_architectures() {
+        let filter = build_filter();
+        // syscall numbers mean something else there
+        for nr in TRACED_SYSCALLS {
+            assert_eq!(
+                run(&filter, AUDIT_ARCH_I386, *nr as u32),
+                libc::SECCOMP_RET_ALLOW,
+                "syscall {nr}"
+            );
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use nix::{errno::Errno, unistd::Pid};
+
+// from linux/audit.h
+pub(crate) const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+
+/// A syscall as seen when entering it, either from a syscall-enter-stop or
+/// from a seccomp stop.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SyscallEntry {
+    /// One of the `AUDIT_ARCH_*` values: a 64-bit tracee can still make
+    /// 32-bit syscalls, with different numbers.
+    pub(crate) arch: u32,
+    pub(crate) nr: i64,
+    pub(crate) args: [u64; 6],
+}
+
+impl SyscallEntry {
+    /// Whether `nr` and `args` mean what the `libc::SYS_*` constants
+    /// we're built with say they mean.
+    pub(crate) fn is_native(&self) -> bool {
+        self.arch == AUDIT_ARCH_X86_64
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SyscallExit {
+    pub(crate) ret: i64,
+    pub(crate) is_error: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SyscallStop {
+    Entry(SyscallEntry),
+    Exit(SyscallExit),
+    /// Not stopped at a syscall at all (signal-delivery-stop, etc.)
+    None,
+}
+
+/// Asks the kernel where `pid` is stopped, with PTRACE_GET_SYSCALL_INFO
+/// (Linux 5.3+). Unlike reading registers, this tells entry and exit apart.
+pub(crate) fn get(pid: Pid) -> nix::Result<SyscallStop> {
+    let mut info: libc::ptrace_syscall_info = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GET_SYSCALL_INFO,
+            pid.as_raw(),
+            std::mem::size_of_val(&info),
+            &mut info as *mut libc::ptrace_syscall_info,
+        )
+    };
+    Errno::result(ret)?;
+
+    let stop = unsafe {
+        match info.op {
+            libc::PTRACE_SYSCALL_INFO_ENTRY => SyscallStop::Entry(SyscallEntry {
+                arch: info.arch,
+                nr: info.u.entry.nr as _,
+                args: info.u.entry.args,
+            }),
+            libc::PTRACE_SYSCALL_INFO_SECCOMP => SyscallStop::Entry(SyscallEntry {
+                arch: info.arch,
+                nr: info.u.seccomp.nr as _,
+                args: info.u.seccomp.args,
+            }),
+            libc::PTRACE_SYSCALL_INFO_EXIT => SyscallStop::Exit(SyscallExit {
+                ret: info.u.exit.sval,
+                is_error: info.u.exit.is_error != 0,
+            }),
+            _ => SyscallStop::None,
+        }
+    };
+    Ok(stop)
+}
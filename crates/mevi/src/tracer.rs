@@ -8,27 +8,45 @@ use std::{
     },
     process::Command,
     sync::{mpsc, Arc},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use color_eyre::Result;
 use humansize::{make_format, BINARY};
 use libc::{sockaddr_un, user_regs_struct};
-use mevi_common::{MemState, MeviEvent, TraceeId, TraceePayload};
+use mevi_common::{
+    Attribute, HugePageAdvice, Mapping, MappingBacking, MappingMap, MappingOrigin, MemState,
+    MeviEvent, Protection, TraceeId, TraceePayload,
+};
 use nix::{
     errno::Errno,
     sys::{
         mman::{MapFlags, ProtFlags},
         ptrace,
         signal::Signal,
+        statfs::{statfs, HUGETLBFS_MAGIC, TMPFS_MAGIC},
         wait::{waitpid, WaitStatus},
     },
-    unistd::{Pid, SysconfVar},
+    unistd::Pid,
 };
 use passfd::FdPassingExt;
-use procfs::process::{MMPermissions, MMapPath, MemoryPageFlags, PageInfo};
+use procfs::process::{MMPermissions, MMapPath, MemoryMap, VmFlags};
+use rangemap::RangeSet;
 use tracing::{debug, info, trace, warn};
-use userfaultfd::{raw, FeatureFlags, IoctlFlags, Uffd};
+use userfaultfd::{raw, FeatureFlags, IoctlFlags, RegisterMode, Uffd};
+
+use crate::{
+    console::Console,
+    content::mapping_name,
+    options::Options,
+    pagemap::Scanner,
+    remote, seccomp,
+    syscall_info::{self, SyscallEntry, SyscallExit, SyscallStop},
+    userfault::{Backing, UffdLoop, UFFD_USER_MODE_ONLY},
+};
+
+// from linux/mman.h, Linux 6.1+, not in libc yet
+const MADV_COLLAPSE: i32 = 25;
 
 struct MemoryEvent {
     for_tid: TraceeId,
@@ -39,6 +57,14 @@ enum MemoryChange {
     Map {
         range: Range<u64>,
         state: MemState,
+        /// `None` if we don't track its memory, and only report what it is
+        backing: Option<Backing>,
+        mapping: Mapping,
+    },
+    /// `mprotect`
+    Protect {
+        range: Range<u64>,
+        prot: Protection,
     },
     Remap {
         old_range: Range<u64>,
@@ -50,16 +76,81 @@ enum MemoryChange {
     PageOut {
         range: Range<u64>,
     },
+    /// Pages may have been merged into transparent huge pages, swapped out
+    /// or swapped in, without any page faults
+    Recheck {
+        ranges: Vec<Range<u64>>,
+    },
+    /// `process_mrelease`: the process is dying, and its memory was freed
+    /// ahead of time
+    Release,
+    /// `MADV_FREE`: resident pages may be reclaimed later
+    LazyFree {
+        range: Range<u64>,
+    },
+    Attribute {
+        range: Range<u64>,
+        attr: Attribute,
+    },
+    /// mlock and friends, or munlock and friends if `mode` is None
+    Lock {
+        ranges: Vec<Range<u64>>,
+        mode: Option<LockMode>,
+        /// Whether it's for future mappings too (`MCL_FUTURE`, or
+        /// `munlockall`, which unlocks everything that's locked and leaves
+        /// `ranges` empty)
+        future: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LockMode {
+    /// The whole range is faulted in right away
+    Populate,
+    /// `MLOCK_ONFAULT` / `MCL_ONFAULT`: pages are locked as they're faulted in
+    OnFault,
 }
 
 pub(crate) struct Tracer {
     listener: Arc<UnixListener>,
     tx: mpsc::SyncSender<MeviEvent>,
     tracees: HashMap<TraceeId, Tracee>,
+    uffds: UffdLoop,
+    opts: Options,
+    bench: Option<BenchStats>,
+}
+
+/// Counts how often tracees stopped, to measure tracing overhead
+struct BenchStats {
+    started: Instant,
+    syscall_stops: u64,
+    seccomp_stops: u64,
+    event_stops: u64,
+    signal_stops: u64,
+}
+
+impl BenchStats {
+    fn report(&self) {
+        let elapsed = self.started.elapsed();
+        let total = self.syscall_stops + self.seccomp_stops + self.event_stops + self.signal_stops;
+        warn!(
+            "bench: ran for {elapsed:?}, {total} ptrace stops ({} syscall, {} seccomp, {} event, {} signal), {:.0} stops/s",
+            self.syscall_stops,
+            self.seccomp_stops,
+            self.event_stops,
+            self.signal_stops,
+            total as f64 / elapsed.as_secs_f64(),
+        );
+    }
 }
 
 impl Tracer {
-    pub(crate) fn new(tx: mpsc::SyncSender<MeviEvent>, listener: UnixListener) -> Result<Self> {
+    pub(crate) fn new(
+        tx: mpsc::SyncSender<MeviEvent>,
+        listener: UnixListener,
+        uffds: UffdLoop,
+        opts: &Options,
+    ) -> Result<Self> {
         // set ourselves as the child subreaper
         let errno = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
         if errno < 0 {
@@ -69,14 +160,9 @@ impl Tracer {
             );
         }
 
-        let mut args = std::env::args();
-        // skip our own name
-        args.next().unwrap();
-
-        let mut cmd = Command::new(args.next().unwrap());
-        for arg in args {
-            cmd.arg(arg);
-        }
+        let mut cmd = Command::new(&opts.command[0]);
+        cmd.args(&opts.command[1..]);
+        let console = Console::prepare(&mut cmd, opts.stdio)?;
 
         unsafe {
             cmd.pre_exec(|| {
@@ -84,33 +170,67 @@ impl Tracer {
                 Ok(())
             });
         }
+        if opts.seccomp {
+            seccomp::install_on_exec(&mut cmd);
+        }
 
-        let child = cmd.spawn().unwrap();
+        let start = Instant::now();
+        let mut child = cmd.spawn().unwrap();
+        console.forward(&mut child, &tx, start);
 
         let pid = Pid::from_raw(child.id() as _);
         std::mem::forget(child);
 
+        // that's either the SIGTRAP from exec, or the SIGSTOP the child sends
+        // itself after installing the seccomp filter
         let res = waitpid(pid, None)?;
         trace!("first waitpid: {res:?}");
 
-        ptrace::setoptions(
-            pid,
-            ptrace::Options::PTRACE_O_TRACESYSGOOD
-                | ptrace::Options::PTRACE_O_TRACECLONE
-                | ptrace::Options::PTRACE_O_TRACEFORK
-                | ptrace::Options::PTRACE_O_TRACEVFORK
-                | ptrace::Options::PTRACE_O_TRACEVFORKDONE
-                | ptrace::Options::PTRACE_O_TRACEEXEC
-                | ptrace::Options::PTRACE_O_TRACEEXIT
-                | ptrace::Options::PTRACE_O_EXITKILL,
-        )?;
-        ptrace::syscall(pid, None)?;
-
-        Ok(Self {
+        let mut ptrace_opts = ptrace::Options::PTRACE_O_TRACESYSGOOD
+            | ptrace::Options::PTRACE_O_TRACECLONE
+            | ptrace::Options::PTRACE_O_TRACEFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+            | ptrace::Options::PTRACE_O_TRACEEXEC
+            | ptrace::Options::PTRACE_O_TRACEEXIT
+            | ptrace::Options::PTRACE_O_EXITKILL;
+        if opts.seccomp {
+            ptrace_opts |= ptrace::Options::PTRACE_O_TRACESECCOMP;
+        }
+        ptrace::setoptions(pid, ptrace_opts)?;
+
+        let tracer = Self {
+            uffds,
             tx,
             tracees: Default::default(),
             listener: Arc::new(listener),
-        })
+            opts: opts.clone(),
+            bench: opts.bench.then_some(BenchStats {
+                started: start,
+                syscall_stops: 0,
+                seccomp_stops: 0,
+                event_stops: 0,
+                signal_stops: 0,
+            }),
+        };
+        tracer.resume(pid, None)?;
+        Ok(tracer)
+    }
+
+    /// Resumes a stopped tracee. With the seccomp filter, it only needs to
+    /// stop at the next traced syscall, unless it's in the middle of one and
+    /// we want to see it exit.
+    fn resume(&self, pid: Pid, sig: Option<Signal>) -> nix::Result<()> {
+        let in_syscall = self
+            .tracees
+            .get(&pid.into())
+            .map(|t| t.syscall.is_some())
+            .unwrap_or_default();
+        if self.opts.seccomp && !in_syscall {
+            ptrace::cont(pid, sig)
+        } else {
+            ptrace::syscall(pid, sig)
+        }
     }
 
     pub(crate) fn run(&mut self) -> Result<()> {
@@ -128,6 +248,18 @@ impl Tracer {
             };
 
             tracing::debug!("wait_status: {:?}", wait_status);
+            if let Some(stats) = &mut self.bench {
+                match wait_status {
+                    WaitStatus::PtraceSyscall(_) => stats.syscall_stops += 1,
+                    WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_SECCOMP) => {
+                        stats.seccomp_stops += 1
+                    }
+                    WaitStatus::PtraceEvent(..) => stats.event_stops += 1,
+                    WaitStatus::Stopped(..) => stats.signal_stops += 1,
+                    _ => {}
+                }
+            }
+
             match wait_status {
                 WaitStatus::Stopped(pid, sig) => {
                     let tid: TraceeId = pid.into();
@@ -143,15 +275,15 @@ impl Tracer {
                     match sig {
                         Signal::SIGTRAP => {
                             // probably ptrace stuff?
-                            ptrace::syscall(pid, None)?;
+                            self.resume(pid, None)?;
                         }
                         Signal::SIGSTOP => {
                             // probably a process freshly cloned or forked
-                            ptrace::syscall(pid, None)?;
+                            self.resume(pid, None)?;
                         }
                         _ => {
                             // probably not ptrace stuff, forward the signal?
-                            ptrace::syscall(pid, sig)?;
+                            self.resume(pid, Some(sig))?;
                         }
                     }
                     continue;
@@ -162,25 +294,50 @@ impl Tracer {
                     } else {
                         warn!("{pid} exited with non-zero status {status}");
                     }
+                    self.forget(pid.into());
                     let ev = MeviEvent::TraceeEvent(pid.into(), TraceePayload::Exit);
                     self.tx.send(ev).unwrap();
                 }
                 WaitStatus::PtraceSyscall(pid) => {
                     let tid: TraceeId = pid.into();
-                    debug!("{tid} in sys_enter / sys_exit");
+                    let stop = match syscall_info::get(pid) {
+                        Ok(stop) => stop,
+                        Err(nix::errno::Errno::ESRCH) => {
+                            info!("{tid} exited while we were spying on its syscalls, that's ok");
+                            continue;
+                        }
+                        Err(e) => panic!("{tid} PTRACE_GET_SYSCALL_INFO failed: {e:?}"),
+                    };
+                    debug!("{tid} in {stop:?}");
 
                     let tracee = self.tracees.entry(tid).or_insert_with(|| Tracee {
-                        was_in_syscall: false,
+                        syscall: None,
+                        user_faults_only: false,
+                        execed: false,
+                        forked_from: None,
                         tid,
                         kind: TraceeKind::Fresh,
                     });
 
-                    if tracee.was_in_syscall {
-                        tracee.was_in_syscall = false;
+                    if let SyscallStop::Exit(exit) = stop {
+                        let event = match tracee.syscall.take() {
+                            Some(entry) => tracee.on_sys_exit(
+                                entry,
+                                exit,
+                                &self.tx,
+                                &self.listener,
+                                &self.uffds,
+                                &self.opts,
+                            )?,
+                            None => {
+                                // we attached (or the tracee forked) in the
+                                // middle of that one
+                                debug!("{tid} exiting a syscall we didn't see enter, ignoring");
+                                None
+                            }
+                        };
 
-                        if let Some(MemoryEvent { for_tid, change }) =
-                            tracee.on_sys_exit(&self.tx, &self.listener)?
-                        {
+                        if let Some(MemoryEvent { for_tid, change }) = event {
                             if matches!(tracee.kind, TraceeKind::Fresh) {
                                 warn!(
                                     "{} unknown tracee kind, and Mapped, assuming process",
@@ -189,36 +346,95 @@ impl Tracer {
                             }
 
                             match change {
-                                MemoryChange::Map { range, mut state } => {
+                                MemoryChange::Map {
+                                    range,
+                                    mut state,
+                                    backing,
+                                    mapping,
+                                } => {
                                     let formatter = make_format(BINARY);
                                     info!(
                                         "{tid} => {for_tid} mapping {range:x?} ({}) with {state:?}",
                                         formatter(range.end - range.start)
                                     );
-                                    let target = self.tracees.get(&for_tid).unwrap();
-                                    match &target.kind {
-                                        TraceeKind::Fresh => unreachable!(),
-                                        TraceeKind::Process { uffd, .. } => {
-                                            if let Err(e) = uffd.register(
-                                                range.start as _,
-                                                (range.end - range.start) as _,
-                                            ) {
-                                                tracing::warn!(
+                                    let target = self.tracees.get_mut(&for_tid).unwrap();
+                                    if let TraceeKind::Process { mappings, .. } = &mut target.kind {
+                                        mappings.insert(range.clone(), mapping.clone());
+                                    }
+                                    self.tx.send(MeviEvent::TraceeEvent(
+                                        for_tid,
+                                        TraceePayload::Mapped {
+                                            range: range.clone(),
+                                            mapping,
+                                        },
+                                    ))?;
+                                    // untracked mappings only get labelled
+                                    if let Some(backing) = backing {
+                                        match &mut target.kind {
+                                            TraceeKind::Fresh => unreachable!(),
+                                            TraceeKind::Untracked => {
+                                                state = MemState::Untracked;
+                                            }
+                                            TraceeKind::Process {
+                                                uffd,
+                                                registered,
+                                                features,
+                                                lock_future,
+                                                locked,
+                                                ..
+                                            } => {
+                                                self.uffds.set_backing(
+                                                    for_tid,
+                                                    range.clone(),
+                                                    backing,
+                                                );
+                                                if let Err(e) =
+                                                    register(uffd, &range, backing, *features)
+                                                {
+                                                    tracing::warn!(
                                                     "failed to register {range:?} with uffd: {e:?}"
                                                 );
-                                                state = MemState::Untracked;
+                                                    state = MemState::Untracked;
+                                                } else {
+                                                    registered.insert(range.clone());
+                                                }
+
+                                                // after mlockall(MCL_FUTURE)
+                                                if let Some(mode) = *lock_future {
+                                                    locked.insert(range.clone());
+                                                    self.tx.send(MeviEvent::TraceeEvent(
+                                                        for_tid,
+                                                        TraceePayload::SetAttribute {
+                                                            range: range.clone(),
+                                                            attr: Attribute::Locked(true),
+                                                        },
+                                                    ))?;
+                                                    if mode == LockMode::Populate
+                                                        && state == MemState::NotResident
+                                                    {
+                                                        state = match backing {
+                                                            Backing::Hugetlb { .. } => {
+                                                                MemState::HugeResident
+                                                            }
+                                                            _ => MemState::Resident,
+                                                        };
+                                                    }
+                                                }
+                                            }
+                                            TraceeKind::Thread { pid } => {
+                                                panic!("thread {for_tid} of process {pid} mapping memory should show up in the parent");
                                             }
                                         }
-                                        TraceeKind::Thread { pid } => {
-                                            panic!("thread {for_tid} of process {pid} mapping memory should show up in the parent");
+
+                                        let payload =
+                                            TraceePayload::MemStateChange { range, state };
+                                        if state == MemState::Untracked {
+                                            self.tx
+                                                .send(MeviEvent::TraceeEvent(for_tid, payload))?;
+                                        } else {
+                                            self.uffds.send(for_tid, payload);
                                         }
                                     }
-
-                                    let ev = MeviEvent::TraceeEvent(
-                                        for_tid,
-                                        TraceePayload::MemStateChange { range, state },
-                                    );
-                                    self.tx.send(ev)?;
                                 }
                                 MemoryChange::Remap {
                                     old_range,
@@ -227,38 +443,185 @@ impl Tracer {
                                     // note: uffd follows remaps, we don't need to
                                     // unregister or re-register anything
 
-                                    let ev = MeviEvent::TraceeEvent(
-                                        for_tid,
-                                        TraceePayload::Remap {
-                                            old_range,
-                                            new_range,
-                                        },
-                                    );
-                                    self.tx.send(ev)?;
+                                    let target = self.tracees.get_mut(&for_tid).unwrap();
+                                    if let TraceeKind::Process {
+                                        locked, mappings, ..
+                                    } = &mut target.kind
+                                    {
+                                        // locks move along with the mapping
+                                        if locked.overlaps(&old_range) {
+                                            locked.remove(old_range.clone());
+                                            locked.insert(new_range.clone());
+                                        }
+                                        // whoever reports the move may not
+                                        // report what it grew by
+                                        if let Some(mapping) =
+                                            mappings.get(&old_range.start).cloned()
+                                        {
+                                            mappings.remove(old_range.clone());
+                                            mappings.insert(new_range.clone(), mapping.clone());
+                                            self.tx.send(MeviEvent::TraceeEvent(
+                                                for_tid,
+                                                TraceePayload::Mapped {
+                                                    range: new_range.clone(),
+                                                    mapping,
+                                                },
+                                            ))?;
+                                        }
+                                    }
+                                    let registered = match &mut target.kind {
+                                        TraceeKind::Process { registered, .. } => Some(registered),
+                                        _ => None,
+                                    }
+                                    .filter(|registered| registered.overlaps(&old_range));
+
+                                    if let Some(registered) = registered {
+                                        registered.remove(old_range.clone());
+                                        registered.insert(new_range.clone());
+
+                                        // the uffd handler reports the move (and
+                                        // the unmapped tail if it shrunk), but
+                                        // not the pages it grew by.
+                                        let old_len = old_range.end - old_range.start;
+                                        let grown = new_range.start + old_len..new_range.end;
+                                        if grown.start < grown.end {
+                                            self.uffds.send(
+                                                for_tid,
+                                                TraceePayload::MemStateChange {
+                                                    range: grown,
+                                                    state: MemState::NotResident,
+                                                },
+                                            );
+                                        }
+                                    } else {
+                                        let ev = MeviEvent::TraceeEvent(
+                                            for_tid,
+                                            TraceePayload::Remap {
+                                                old_range,
+                                                new_range,
+                                            },
+                                        );
+                                        self.tx.send(ev)?;
+                                    }
                                 }
                                 MemoryChange::Unmap { range } => {
                                     // note: uffd follows unmaps, we don't need
-                                    // to unregister anything.
-
+                                    // to unregister anything, and it reports
+                                    // them itself for registered ranges.
+
+                                    let target = self.tracees.get_mut(&for_tid).unwrap();
+                                    for range in target.unregistered_parts(&range) {
+                                        let ev = MeviEvent::TraceeEvent(
+                                            for_tid,
+                                            TraceePayload::Unmap { range },
+                                        );
+                                        self.tx.send(ev)?;
+                                    }
+                                    if let TraceeKind::Process {
+                                        registered,
+                                        locked,
+                                        mappings,
+                                        ..
+                                    } = &mut target.kind
+                                    {
+                                        registered.remove(range.clone());
+                                        locked.remove(range.clone());
+                                        mappings.remove(range);
+                                    }
+                                }
+                                MemoryChange::Recheck { ranges } => {
+                                    // pagemap is the only way to find out
+                                    debug!("{for_tid} may have changed {ranges:x?} behind our back, rechecking");
+                                    self.uffds.recheck(for_tid, &ranges);
+                                }
+                                MemoryChange::Release => {
+                                    self.uffds.release(for_tid);
+                                }
+                                MemoryChange::LazyFree { range } => {
+                                    // unregistered memory is untracked anyway
+                                    self.uffds.lazy_free(for_tid, range);
+                                }
+                                MemoryChange::Protect { range, prot } => {
+                                    let payload = TraceePayload::Protect { range, prot };
+                                    let target = self.tracees.get_mut(&for_tid).unwrap();
+                                    if let TraceeKind::Process { mappings, .. } = &mut target.kind {
+                                        payload.apply_to_mappings(mappings);
+                                    }
+                                    self.tx.send(MeviEvent::TraceeEvent(for_tid, payload))?;
+                                }
+                                MemoryChange::Attribute { range, attr } => {
                                     let ev = MeviEvent::TraceeEvent(
                                         for_tid,
-                                        TraceePayload::Unmap { range },
+                                        TraceePayload::SetAttribute { range, attr },
                                     );
                                     self.tx.send(ev)?;
                                 }
+                                MemoryChange::Lock {
+                                    ranges,
+                                    mode,
+                                    future,
+                                } => {
+                                    let mut ranges = ranges;
+                                    let target = self.tracees.get_mut(&for_tid).unwrap();
+                                    if let TraceeKind::Process {
+                                        lock_future,
+                                        locked,
+                                        ..
+                                    } = &mut target.kind
+                                    {
+                                        if future {
+                                            *lock_future = mode;
+                                        }
+                                        if mode.is_none() && future {
+                                            // munlockall: whatever we said was locked
+                                            ranges = locked.iter().cloned().collect();
+                                            locked.clear();
+                                        }
+                                        for range in &ranges {
+                                            if mode.is_some() {
+                                                locked.insert(range.clone());
+                                            } else {
+                                                locked.remove(range.clone());
+                                            }
+                                        }
+                                    }
+
+                                    for range in &ranges {
+                                        let ev = MeviEvent::TraceeEvent(
+                                            for_tid,
+                                            TraceePayload::SetAttribute {
+                                                range: range.clone(),
+                                                attr: Attribute::Locked(mode.is_some()),
+                                            },
+                                        );
+                                        self.tx.send(ev)?;
+                                    }
+                                    if mode == Some(LockMode::Populate) && !ranges.is_empty() {
+                                        // mlock faults in the whole range, but
+                                        // the kernel may do it without going
+                                        // through uffd (e.g. for pages that
+                                        // were swapped out)
+                                        debug!("{for_tid} locked memory, rechecking it");
+                                        self.uffds.recheck(for_tid, &ranges);
+                                    }
+                                }
                                 MemoryChange::PageOut { range } => {
-                                    let ev = MeviEvent::TraceeEvent(
-                                        for_tid,
-                                        TraceePayload::MemStateChange {
-                                            range,
-                                            state: MemState::NotResident,
-                                        },
-                                    );
-                                    self.tx.send(ev).unwrap();
+                                    // registered ranges get a uffd remove event
+                                    let target = self.tracees.get(&for_tid).unwrap();
+                                    for range in target.unregistered_parts(&range) {
+                                        let ev = MeviEvent::TraceeEvent(
+                                            for_tid,
+                                            TraceePayload::MemStateChange {
+                                                range,
+                                                state: MemState::NotResident,
+                                            },
+                                        );
+                                        self.tx.send(ev).unwrap();
+                                    }
                                 }
                             }
                         }
-                        if let Err(e) = ptrace::syscall(pid, None) {
+                        if let Err(e) = self.resume(pid, None) {
                             if e == nix::errno::Errno::ESRCH {
                                 // the process has exited, we don't care
                                 info!("{pid} exited while we spied");
@@ -267,7 +630,9 @@ impl Tracer {
                             }
                         }
                     } else {
-                        tracee.was_in_syscall = true;
+                        if let SyscallStop::Entry(entry) = stop {
+                            tracee.syscall = Some(entry);
+                        }
                         match ptrace::syscall(pid, None) {
                             Ok(_) => {}
                             Err(e) => {
@@ -288,18 +653,66 @@ impl Tracer {
                     let child_tid = TraceeId(ptrace::getevent(pid)? as _);
 
                     match event {
+                        libc::PTRACE_EVENT_SECCOMP => {
+                            // about to run a syscall our filter cares about,
+                            // stop again when it exits.
+                            let stop = match syscall_info::get(pid) {
+                                Ok(stop) => stop,
+                                Err(nix::errno::Errno::ESRCH) => {
+                                    info!(
+                                        "{tid} exited before we could see its syscall, that's ok"
+                                    );
+                                    self.forget(tid);
+                                    continue;
+                                }
+                                Err(e) => panic!("{tid} PTRACE_GET_SYSCALL_INFO failed: {e:?}"),
+                            };
+                            debug!("{tid} in seccomp stop: {stop:?}");
+                            let tracee = self.tracees.entry(tid).or_insert_with(|| Tracee {
+                                syscall: None,
+                                user_faults_only: false,
+                                execed: false,
+                                forked_from: None,
+                                tid,
+                                kind: TraceeKind::Fresh,
+                            });
+                            if let SyscallStop::Entry(entry) = stop {
+                                tracee.syscall = Some(entry);
+                            }
+                        }
                         libc::PTRACE_EVENT_FORK => {
                             info!("{tid} forked into {child_tid} (with {sig})");
+                            // the child connects on its own, but our pages
+                            // are now shared with it
+                            let pid = match self.tracees.get(&tid).map(|t| &t.kind) {
+                                Some(TraceeKind::Thread { pid }) => *pid,
+                                _ => tid,
+                            };
+                            self.uffds.link(pid, child_tid);
                             // don't replace whatever we have in `self.tracees`,
                             // sometimes we get that event AFTER getting some
                             // sys_enter/sys_exit from the child
+                            self.tracees
+                                .entry(child_tid)
+                                .or_insert_with(|| Tracee {
+                                    syscall: None,
+                                    user_faults_only: false,
+                                    execed: false,
+                                    forked_from: None,
+                                    tid: child_tid,
+                                    kind: TraceeKind::Fresh,
+                                })
+                                .forked_from = Some(pid);
                         }
                         libc::PTRACE_EVENT_VFORK => {
                             info!("{tid} vforked into {child_tid} (with {sig})");
                             self.tracees.insert(
                                 child_tid,
                                 Tracee {
-                                    was_in_syscall: false,
+                                    syscall: None,
+                                    user_faults_only: false,
+                                    execed: false,
+                                    forked_from: None,
                                     tid: child_tid,
                                     kind: TraceeKind::Fresh {},
                                 },
@@ -316,12 +729,17 @@ impl Tracer {
                                     panic!("{tid} cloned, but we didn't know about that process");
                                 }
                             };
+                            // threads share their process's uffd
+                            let user_faults_only = tracee.user_faults_only;
                             if let TraceeKind::Thread { pid } = &tracee.kind {
                                 info!("{tid} is a thread of {pid}, so it made a sibling!");
                                 self.tracees.insert(
                                     child_tid,
                                     Tracee {
-                                        was_in_syscall: false,
+                                        syscall: None,
+                                        user_faults_only,
+                                        execed: false,
+                                        forked_from: None,
                                         tid: child_tid,
                                         kind: TraceeKind::Thread { pid: *pid },
                                     },
@@ -330,7 +748,10 @@ impl Tracer {
                                 self.tracees.insert(
                                     child_tid,
                                     Tracee {
-                                        was_in_syscall: false,
+                                        syscall: None,
+                                        user_faults_only,
+                                        execed: false,
+                                        forked_from: None,
                                         tid: child_tid,
                                         kind: TraceeKind::Thread { pid: tid },
                                     },
@@ -347,6 +768,8 @@ impl Tracer {
                             };
                             // this clear out the uffd, too
                             tracee.kind = TraceeKind::Fresh;
+                            tracee.execed = true;
+                            self.uffds.remove(tid);
                             self.tx
                                 .send(MeviEvent::TraceeEvent(tid, TraceePayload::Exec))
                                 .unwrap();
@@ -364,11 +787,18 @@ impl Tracer {
                         }
                     }
 
-                    ptrace::syscall(pid, None)?;
+                    if let Err(e) = self.resume(pid, None) {
+                        if e == nix::errno::Errno::ESRCH {
+                            info!("{tid} exited while we handled its event, that's ok");
+                        } else {
+                            panic!("{tid} resuming after event {event} failed: {e:?}");
+                        }
+                    }
                 }
                 WaitStatus::Signaled(pid, signal, core_dump) => {
                     let tid: TraceeId = pid.into();
                     info!("{tid} was terminated with signal {signal} with, WCOREDUMP({core_dump})");
+                    self.forget(tid);
                     let ev = MeviEvent::TraceeEvent(tid, TraceePayload::Exit);
                     self.tx.send(ev).unwrap();
                 }
@@ -378,15 +808,41 @@ impl Tracer {
             }
         }
 
+        if let Some(stats) = &self.bench {
+            stats.report();
+        }
+
         info!("will exit in a few");
         // not great, but this gives time for the last few mesages to reach the frontend
         std::thread::sleep(Duration::from_millis(500));
         std::process::exit(0);
     }
+
+    /// Drops everything we know about a tracee that's gone for good, including
+    /// its uffd if it's a process.
+    fn forget(&mut self, tid: TraceeId) {
+        match self.tracees.remove(&tid) {
+            Some(Tracee {
+                kind: TraceeKind::Thread { pid },
+                ..
+            }) => self.uffds.forget_thread(pid, tid),
+            // even without a uffd yet, it may have forked from another tracee
+            _ => self.uffds.remove(tid),
+        }
+    }
 }
 
 struct Tracee {
-    was_in_syscall: bool,
+    /// The syscall the tracee is in the middle of, if any
+    syscall: Option<SyscallEntry>,
+    /// Whether the uffd of its process only sees faults from userland
+    user_faults_only: bool,
+    /// Whether it exec'd since we first saw it, after which its memory has
+    /// nothing to do with its parent's anymore
+    execed: bool,
+    /// The process it forked from, as told by that process's
+    /// `PTRACE_EVENT_FORK`
+    forked_from: Option<TraceeId>,
     tid: TraceeId,
     kind: TraceeKind,
 }
@@ -396,30 +852,69 @@ enum TraceeKind {
     Fresh,
 
     // it's a process, we got an uffd for it
-    Process { heap_range: Range<u64>, uffd: Uffd },
+    Process {
+        heap_range: Range<u64>,
+        uffd: Uffd,
+        /// What's registered with `uffd`: changes to those ranges are
+        /// reported by the uffd handler, in order with page faults.
+        registered: RangeSet<u64>,
+        /// What we negotiated for `uffd`
+        features: FeatureFlags,
+        /// How new mappings get locked, after `mlockall(MCL_FUTURE)`
+        lock_future: Option<LockMode>,
+        /// What we've told the relay is locked, for `munlockall`
+        locked: RangeSet<u64>,
+        /// What we've told the relay each mapping is, so it's still known
+        /// after `mremap`
+        mappings: MappingMap,
+    },
 
     // it's a thread of a process we know about
-    Thread { pid: TraceeId },
+    Thread {
+        pid: TraceeId,
+    },
+
+    // it's a process, but we couldn't watch its uffd: its memory is reported
+    // as untracked
+    Untracked,
 }
 
 impl Tracee {
+    /// The parts of `range` that aren't registered with our uffd, and that
+    /// we have to report changes for from ptrace.
+    fn unregistered_parts(&self, range: &Range<u64>) -> Vec<Range<u64>> {
+        match &self.kind {
+            TraceeKind::Process { registered, .. } => registered.gaps(range).collect(),
+            _ => vec![range.clone()],
+        }
+    }
+
     fn on_sys_exit(
         &mut self,
+        entry: SyscallEntry,
+        exit: SyscallExit,
         tx: &mpsc::SyncSender<MeviEvent>,
         listener: &Arc<UnixListener>,
+        uffds: &UffdLoop,
+        opts: &Options,
     ) -> Result<Option<MemoryEvent>> {
-        let regs = ptrace::getregs(self.tid.into())?;
-        trace!("on sys_exit: {regs:?}");
-        let ret = regs.rax;
+        trace!("on sys_exit: {entry:?} => {exit:?}");
+        if !entry.is_native() {
+            // the syscall numbers would mean something else entirely
+            debug!("{} ignoring non-native syscall {entry:?}", self.tid);
+            return Ok(None);
+        }
+        let ret = exit.ret as u64;
 
         if matches!(self.kind, TraceeKind::Fresh) {
-            match regs.orig_rax as _ {
+            match entry.nr {
                 libc::SYS_execve => {
                     // bad idea, we're about to replace all memory mappings anyway
                 }
                 syscall_nr => {
                     info!("{} connecting out of syscall nr. {syscall_nr}", self.tid);
-                    if let Err(e) = self.connect(regs, tx, listener) {
+                    let regs = ptrace::getregs(self.tid.into())?;
+                    if let Err(e) = self.connect(regs, tx, listener, uffds, opts) {
                         if let Some(nix_err) = e.downcast_ref::<nix::Error>() {
                             if nix_err == &nix::Error::ESRCH {
                                 // the process has exited, we don't care
@@ -442,49 +937,92 @@ impl Tracee {
                 // nevermind then
                 return Ok(None);
             }
-            TraceeKind::Process { .. } => self.tid,
+            TraceeKind::Process { .. } | TraceeKind::Untracked => self.tid,
         };
 
-        match regs.orig_rax as i64 {
+        if exit.is_error {
+            // only for the syscalls we stop on, see `--trace-all-syscalls`
+            if self.user_faults_only && exit.ret == -(libc::EFAULT as i64) {
+                // not necessarily our fault, but that's the most likely cause
+                let message = format!(
+                    "syscall nr. {} failed with EFAULT, the kernel probably touched memory that was never faulted in from userland (only user faults are handled)",
+                    entry.nr
+                );
+                warn!("{} {message}", self.tid);
+                tx.send(MeviEvent::TraceeEvent(
+                    for_tid,
+                    TraceePayload::Warning { message },
+                ))?;
+            }
+
+            // failed syscalls don't change the memory map
+            return Ok(None);
+        }
+
+        match entry.nr {
             libc::SYS_mmap => {
-                let addr_in = regs.rdi;
-                let len = regs.rsi;
-                let prot = regs.rdx;
-                let flags = regs.r10;
-                let fd = regs.r8 as i32;
+                let addr_in = entry.args[0];
+                let len = entry.args[1];
+                let prot = entry.args[2];
+                let flags = entry.args[3];
+                let fd = entry.args[4] as i32;
                 let map_flags = MapFlags::from_bits(flags as _).unwrap();
                 let prot_flags = ProtFlags::from_bits(prot as _).unwrap();
-                let _ = (map_flags, prot_flags);
 
-                if fd == -1
-                    && addr_in == 0
+                // everything gets labelled, but only fresh read-write
+                // mappings get tracked
+                let backing = if addr_in == 0
                     && prot_flags.contains(ProtFlags::PROT_READ | ProtFlags::PROT_WRITE)
-                    // && map_flags.contains(MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS)
-                    && map_flags.contains(MapFlags::MAP_ANONYMOUS)
                 {
-                    let start = ret;
-                    if let Some(end) = ret.checked_add(len) {
-                        let range = start..end;
-                        debug!("{} thread of {for_tid} just did mmap {range:x?} addr_in={addr_in:x?} len={len:x?} prot=({prot_flags:?}) flags=({map_flags:?}) fd={fd} ret={ret:x?}", self.tid);
-                        return Ok(Some(MemoryEvent {
-                            for_tid,
-                            change: MemoryChange::Map {
-                                range,
-                                state: if map_flags.contains(MapFlags::MAP_POPULATE) {
-                                    MemState::Resident
-                                } else {
-                                    MemState::NotResident
-                                },
-                            },
-                        }));
-                    }
-                }
+                    mmap_backing(self.tid, map_flags, flags, fd)
+                } else {
+                    None
+                };
+                let range = page_range(ret, len);
+                debug!("{} thread of {for_tid} just did mmap {range:x?} addr_in={addr_in:x?} len={len:x?} prot=({prot_flags:?}) flags=({map_flags:?}) fd={fd} ret={ret:x?} backing={backing:?}", self.tid);
+                return Ok(Some(MemoryEvent {
+                    for_tid,
+                    change: MemoryChange::Map {
+                        range,
+                        state: match backing {
+                            _ if !map_flags.contains(MapFlags::MAP_POPULATE) => {
+                                MemState::NotResident
+                            }
+                            Some(Backing::Hugetlb { .. }) => MemState::HugeResident,
+                            _ => MemState::Resident,
+                        },
+                        backing,
+                        mapping: Mapping {
+                            prot: protection(prot_flags),
+                            flags: map_flag_names(flags),
+                            backing: mmap_mapping_backing(self.tid, map_flags, fd),
+                            origin: MappingOrigin::Mmap,
+                            unplaced_huge_bytes: 0,
+                        },
+                    },
+                }));
+            }
+            libc::SYS_mprotect => {
+                let range = page_range(entry.args[0], entry.args[1]);
+                let prot = ProtFlags::from_bits_truncate(entry.args[2] as _);
+                debug!(
+                    "{} thread of {for_tid} just did mprotect {range:x?} prot=({prot:?})",
+                    self.tid
+                );
+
+                return Ok(Some(MemoryEvent {
+                    for_tid,
+                    change: MemoryChange::Protect {
+                        range,
+                        prot: protection(prot),
+                    },
+                }));
             }
             libc::SYS_mremap => {
-                let addr = regs.rdi;
-                let old_len = regs.rsi;
-                let new_len = regs.rdx;
-                let flags = regs.r10;
+                let addr = entry.args[0];
+                let old_len = entry.args[1];
+                let new_len = entry.args[2];
+                let flags = entry.args[3];
                 let new_addr = ret;
 
                 let old_range = addr..addr + old_len;
@@ -506,8 +1044,8 @@ impl Tracee {
                 }));
             }
             libc::SYS_munmap => {
-                let addr = regs.rdi;
-                let len = regs.rsi;
+                let addr = entry.args[0];
+                let len = entry.args[1];
                 let range = addr..addr + len;
 
                 {
@@ -533,9 +1071,9 @@ impl Tracee {
                 }));
             }
             libc::SYS_madvise => {
-                let addr = regs.rdi;
-                let len = regs.rsi;
-                let advice = regs.rdx as i32;
+                let addr = entry.args[0];
+                let len = entry.args[1];
+                let advice = entry.args[2] as i32;
 
                 match advice {
                     libc::MADV_DONTNEED | libc::MADV_REMOVE => {
@@ -552,16 +1090,194 @@ impl Tracee {
                             },
                         }));
                     }
+                    MADV_COLLAPSE | libc::MADV_COLD | libc::MADV_PAGEOUT | libc::MADV_WILLNEED => {
+                        let range = addr..addr + len;
+                        debug!("{} thread of {for_tid} just did madvise addr={addr:x?} len={len:x?} advice={advice}, rechecking", self.tid);
+
+                        return Ok(Some(MemoryEvent {
+                            for_tid,
+                            change: MemoryChange::Recheck {
+                                ranges: vec![range],
+                            },
+                        }));
+                    }
+                    libc::MADV_FREE => {
+                        debug!("{} thread of {for_tid} just did madvise-free addr={addr:x?} len={len:x?}", self.tid);
+
+                        return Ok(Some(MemoryEvent {
+                            for_tid,
+                            change: MemoryChange::LazyFree {
+                                range: addr..addr + len,
+                            },
+                        }));
+                    }
+                    libc::MADV_HUGEPAGE | libc::MADV_NOHUGEPAGE => {
+                        let advice = if advice == libc::MADV_HUGEPAGE {
+                            HugePageAdvice::Huge
+                        } else {
+                            HugePageAdvice::NoHuge
+                        };
+                        debug!("{} thread of {for_tid} just did madvise addr={addr:x?} len={len:x?} advice={advice:?}", self.tid);
+
+                        return Ok(Some(MemoryEvent {
+                            for_tid,
+                            change: MemoryChange::Attribute {
+                                range: addr..addr + len,
+                                attr: Attribute::HugePages(advice),
+                            },
+                        }));
+                    }
                     _ => {
                         // ignore
                     }
                 }
             }
+            libc::SYS_process_madvise => {
+                let pidfd = entry.args[0] as i32;
+                let iov = entry.args[1] as usize;
+                let vlen = entry.args[2] as usize;
+                let advice = entry.args[3] as i32;
+
+                let Some(target) = pidfd_target(self.tid, pidfd) else {
+                    debug!(
+                        "{} did process_madvise on pidfd {pidfd}, but we can't tell which process that is",
+                        self.tid
+                    );
+                    return Ok(None);
+                };
+
+                // those are the only ones process_madvise accepts
+                if let MADV_COLLAPSE | libc::MADV_COLD | libc::MADV_PAGEOUT | libc::MADV_WILLNEED =
+                    advice
+                {
+                    let mut ranges = vec![];
+                    for i in 0..vlen {
+                        let mut iovec = libc::iovec {
+                            iov_base: std::ptr::null_mut(),
+                            iov_len: 0,
+                        };
+                        remote::read(
+                            self.tid.into(),
+                            iov + i * std::mem::size_of::<libc::iovec>(),
+                            remote::as_bytes_mut(&mut iovec),
+                        )?;
+                        let start = iovec.iov_base as u64;
+                        ranges.push(start..start + iovec.iov_len as u64);
+                    }
+                    debug!("{} thread of {for_tid} just did process_madvise on {target} ranges={ranges:x?} advice={advice}, rechecking", self.tid);
+
+                    return Ok(Some(MemoryEvent {
+                        for_tid: target,
+                        change: MemoryChange::Recheck { ranges },
+                    }));
+                }
+            }
+            libc::SYS_process_mrelease => {
+                let pidfd = entry.args[0] as i32;
+                let Some(target) = pidfd_target(self.tid, pidfd) else {
+                    debug!(
+                        "{} did process_mrelease on pidfd {pidfd}, but we can't tell which process that is",
+                        self.tid
+                    );
+                    return Ok(None);
+                };
+                debug!(
+                    "{} thread of {for_tid} just did process_mrelease on {target}",
+                    self.tid
+                );
+
+                return Ok(Some(MemoryEvent {
+                    for_tid: target,
+                    change: MemoryChange::Release,
+                }));
+            }
+            libc::SYS_mlock | libc::SYS_mlock2 | libc::SYS_munlock => {
+                let range = page_range(entry.args[0], entry.args[1]);
+                let mode = match entry.nr {
+                    libc::SYS_munlock => None,
+                    libc::SYS_mlock2 if entry.args[2] as u32 & libc::MLOCK_ONFAULT != 0 => {
+                        Some(LockMode::OnFault)
+                    }
+                    _ => Some(LockMode::Populate),
+                };
+                debug!(
+                    "{} thread of {for_tid} just did mlock/munlock {range:x?} mode={mode:?}",
+                    self.tid
+                );
+
+                return Ok(Some(MemoryEvent {
+                    for_tid,
+                    change: MemoryChange::Lock {
+                        ranges: vec![range],
+                        mode,
+                        future: false,
+                    },
+                }));
+            }
+            libc::SYS_mlockall => {
+                let flags = entry.args[0] as i32;
+                let mode = if flags & libc::MCL_ONFAULT != 0 {
+                    LockMode::OnFault
+                } else {
+                    LockMode::Populate
+                };
+                debug!(
+                    "{} thread of {for_tid} just did mlockall flags={flags:x?}",
+                    self.tid
+                );
+
+                let ranges = if flags & libc::MCL_CURRENT != 0 {
+                    match procfs::process::Process::new(for_tid.0 as _).and_then(|p| p.maps()) {
+                        Ok(maps) => maps
+                            .into_iter()
+                            // the kernel doesn't lock those
+                            .filter(|map| {
+                                !matches!(
+                                    map.pathname,
+                                    MMapPath::Vdso | MMapPath::Vvar | MMapPath::Vsyscall
+                                ) && map.perms.intersects(
+                                    MMPermissions::READ
+                                        | MMPermissions::WRITE
+                                        | MMPermissions::EXECUTE,
+                                )
+                            })
+                            .map(|map| map.address.0..map.address.1)
+                            .collect(),
+                        Err(e) => {
+                            warn!("{for_tid} did mlockall but we can't read its maps: {e}");
+                            vec![]
+                        }
+                    }
+                } else {
+                    vec![]
+                };
+                return Ok(Some(MemoryEvent {
+                    for_tid,
+                    change: MemoryChange::Lock {
+                        ranges,
+                        mode: Some(mode),
+                        future: flags & libc::MCL_FUTURE != 0,
+                    },
+                }));
+            }
+            libc::SYS_munlockall => {
+                debug!("{} thread of {for_tid} just did munlockall", self.tid);
+
+                // the process knows what it had locked
+                return Ok(Some(MemoryEvent {
+                    for_tid,
+                    change: MemoryChange::Lock {
+                        ranges: vec![],
+                        mode: None,
+                        future: true,
+                    },
+                }));
+            }
             libc::SYS_brk => {
                 // FIXME: calling brk from a thread should mutate the heap of
                 // the whole process
                 if let TraceeKind::Process { heap_range, .. } = &mut self.kind {
-                    if regs.rdi == 0 {
+                    if entry.args[0] == 0 {
                         // just a query: ignore
                     } else {
                         // either growing or shrinking the heap,
@@ -577,6 +1293,18 @@ impl Tracee {
                                 change: MemoryChange::Map {
                                     range: old_top..heap_range.end,
                                     state: MemState::Resident,
+                                    backing: Some(Backing::Anon),
+                                    mapping: Mapping {
+                                        prot: Protection {
+                                            read: true,
+                                            write: true,
+                                            exec: false,
+                                        },
+                                        flags: vec![],
+                                        backing: MappingBacking::Heap,
+                                        origin: MappingOrigin::Brk,
+                                        unplaced_huge_bytes: 0,
+                                    },
                                 },
                             }));
                         }
@@ -611,16 +1339,14 @@ impl Tracee {
         saved_regs: user_regs_struct,
         tx: &mpsc::SyncSender<MeviEvent>,
         listener: &Arc<UnixListener>,
+        uffds: &UffdLoop,
+        opts: &Options,
     ) -> Result<()> {
         let tid = self.tid;
         let pid: Pid = self.tid.into();
+        let started = Instant::now();
 
-        const WORD_SIZE: usize = 8;
-        assert_eq!(
-            std::mem::size_of::<usize>(),
-            WORD_SIZE,
-            "this is all 64-bit only"
-        );
+        assert_eq!(std::mem::size_of::<usize>(), 8, "this is all 64-bit only");
 
         let sys_step = || {
             if let Err(e) = ptrace::syscall(pid, None) {
@@ -630,7 +1356,13 @@ impl Tracee {
                     return Ok(());
                 }
             }
-            let waitres = waitpid(pid, None)?;
+            let mut waitres = waitpid(pid, None)?;
+            while let WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_SECCOMP) = waitres {
+                // the injected syscall went through our own filter, keep going
+                // until the actual syscall stop
+                ptrace::syscall(pid, None)?;
+                waitres = waitpid(pid, None)?;
+            }
             match waitres {
                 WaitStatus::PtraceSyscall(_) => {
                     // good.
@@ -700,42 +1432,40 @@ impl Tracee {
             panic!("failed to allocate staging area: returned MAP_FAILED");
         }
 
-        let write_to_staging = |addr: *const u64, addr_size: usize| -> Result<()> {
-            let num_words = addr_size / WORD_SIZE;
-            for i in 0..num_words {
-                let word = unsafe { addr.add(i) };
-                let word = unsafe { *word };
-                // info!("api word {i}: {:016x}", word);
-                unsafe { ptrace::write(pid, (staging_area + i * WORD_SIZE) as _, word as _)? };
-            }
-            Ok(())
-        };
-
-        let read_from_staging = |addr: *mut u64, addr_size: usize| -> Result<()> {
-            let num_words = addr_size / WORD_SIZE;
-            for i in 0..num_words {
-                let word_dst = unsafe { addr.add(i) };
-                let word = ptrace::read(pid, (staging_area + i * WORD_SIZE) as _)?;
-                // info!("api word {i}: {:016x}", word);
-                unsafe { *word_dst = word as _ };
-            }
-            Ok(())
-        };
-
         debug!("making userfaultfd sycall");
-        let ret = invoke(libc::SYS_userfaultfd, &[0])? as i32;
+        let mut uffd_flags = if opts.user_faults_only {
+            UFFD_USER_MODE_ONLY
+        } else {
+            0
+        };
+        let mut ret = invoke(libc::SYS_userfaultfd, &[uffd_flags])? as i32;
+        if ret == -libc::EPERM && uffd_flags == 0 {
+            // that's `vm.unprivileged_userfaultfd=0`, but user faults are
+            // still allowed.
+            warn!("{tid} userfaultfd failed with EPERM, retrying with user faults only");
+            uffd_flags = UFFD_USER_MODE_ONLY;
+            ret = invoke(libc::SYS_userfaultfd, &[uffd_flags])? as i32;
+        }
         if ret < 0 {
-            panic!("userfaultfd failed with {}", Errno::from_i32(-ret));
+            panic!(
+                "userfaultfd (flags {uffd_flags:x}) failed with {}",
+                Errno::from_i32(-ret)
+            );
         }
         let raw_uffd = ret;
+        self.user_faults_only = uffd_flags & UFFD_USER_MODE_ONLY != 0;
         debug!("making userfaultfd sycall.. done! got fd {raw_uffd}");
 
-        let req_features = FeatureFlags::EVENT_REMAP
+        let mut req_features = FeatureFlags::EVENT_REMAP
             | FeatureFlags::EVENT_REMOVE
             | FeatureFlags::EVENT_UNMAP
             | FeatureFlags::THREAD_ID
             // TODO: this is experimental, figure out if how to do accounting there
-            | FeatureFlags::MISSING_SHMEM;
+            | FeatureFlags::MISSING_SHMEM
+            | uffds.optional_features();
+        if opts.track_writes {
+            req_features |= FeatureFlags::PAGEFAULT_FLAG_WP;
+        }
         let mut api = raw::uffdio_api {
             api: raw::UFFD_API,
             features: req_features.bits(),
@@ -743,10 +1473,7 @@ impl Tracee {
         };
 
         // write the api struct to the staging area
-        write_to_staging(
-            unsafe { std::mem::transmute(&api) },
-            std::mem::size_of_val(&api),
-        )?;
+        remote::write(pid, staging_area, remote::as_bytes(&api))?;
 
         let ret = invoke(
             libc::SYS_ioctl,
@@ -758,10 +1485,7 @@ impl Tracee {
         debug!("ioctl returned {ret}");
 
         // read the api struct back from the staging area
-        read_from_staging(
-            unsafe { std::mem::transmute(&mut api) },
-            std::mem::size_of_val(&api),
-        )?;
+        remote::read(pid, staging_area, remote::as_bytes_mut(&mut api))?;
 
         let supported = IoctlFlags::from_bits(api.ioctls).unwrap();
         debug!("supported ioctls: {supported:?}");
@@ -790,15 +1514,12 @@ impl Tracee {
         let addr_len = 2 + sock_path.len();
         debug!("addr_len = {addr_len}");
 
-        write_to_staging(
-            unsafe { std::mem::transmute(&addr_un) },
-            std::mem::size_of_val(&addr_un),
-        )?;
+        remote::write(pid, staging_area, remote::as_bytes(&addr_un))?;
 
         let accept_jh = std::thread::spawn({
-            let tx = tx.clone();
+            let uffds = uffds.clone();
             let listener = Arc::clone(listener);
-            move || receive_uffd(tx, &listener)
+            move || receive_uffd(&uffds, &listener)
         });
 
         let ret = invoke(
@@ -811,75 +1532,53 @@ impl Tracee {
         debug!("connect returned {ret}");
 
         // now let's write the pid
-        unsafe {
-            ptrace::write(pid, staging_area as _, pid.as_raw() as u64 as _)?;
-        }
+        remote::write(pid, staging_area, &(pid.as_raw() as u64).to_le_bytes())?;
         let ret = invoke(libc::SYS_write, &[sock_fd as _, staging_area as _, 8 as _])? as i32;
         if ret < 0 {
             panic!("write failed with {ret} / {}", Errno::from_i32(-ret));
         }
         debug!("write returned {ret}");
 
-        // this is the big one: sendmsg.
-        let mut msghdr = libc::msghdr {
-            msg_name: std::ptr::null_mut(),
-            msg_namelen: 0,
-            msg_iov: std::ptr::null_mut(),
-            msg_iovlen: 0,
-            msg_control: std::ptr::null_mut(),
-            msg_controllen: 24,
-            msg_flags: 0,
-        };
-
-        // here's our data layout.
+        // this is the big one: sendmsg. here's our data layout.
         //
         // staging_area
         // [ msghdr ] [ payload ] [  iovec  ] [ cmsghdr | cmsg_data ]
         // 0x0        0x100       0x200       0x300
         //
+        // it's all laid out locally, then written in one go.
+        let mut staging = [0u8; 0x400];
 
-        // write payload
-        unsafe {
-            ptrace::write(pid, (staging_area + 0x100) as _, 0x0 as _)?;
-        }
+        let msghdr = libc::msghdr {
+            msg_name: std::ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: (staging_area + 0x200) as _,
+            msg_iovlen: 1,
+            msg_control: (staging_area + 0x300) as _,
+            msg_controllen: 24,
+            msg_flags: 0,
+        };
+        remote::put(&mut staging[0x0..], &msghdr);
+
+        // the payload is 4 zero bytes, which `staging` already is
 
-        // write iovec
         let iovec = libc::iovec {
             iov_base: (staging_area + 0x100) as _,
             iov_len: 4,
         };
-        unsafe {
-            #[allow(clippy::identity_op)]
-            ptrace::write(pid, (staging_area + 0x200 + 0) as _, iovec.iov_base)?;
-            ptrace::write(pid, (staging_area + 0x200 + 8) as _, iovec.iov_len as _)?;
-        }
+        remote::put(&mut staging[0x200..], &iovec);
 
-        msghdr.msg_iov = (staging_area + 0x200) as _;
-        msghdr.msg_iovlen = 1;
-
-        // write cmsghdr
         let cmsghdr = libc::cmsghdr {
             cmsg_len: 20,
             cmsg_level: libc::SOL_SOCKET,
             cmsg_type: libc::SCM_RIGHTS,
         };
-        unsafe {
-            #[allow(clippy::identity_op)]
-            ptrace::write(pid, (staging_area + 0x300 + 0) as _, cmsghdr.cmsg_len as _)?;
-
-            let cmsg_level_ptr: *const u64 = std::mem::transmute(&cmsghdr.cmsg_level);
-            ptrace::write(pid, (staging_area + 0x300 + 8) as _, *cmsg_level_ptr as _)?;
-
-            ptrace::write(pid, (staging_area + 0x300 + 16) as _, raw_uffd as _)?;
-        }
-
-        msghdr.msg_control = (staging_area + 0x300) as _;
-        msghdr.msg_controllen = 24;
+        remote::put(&mut staging[0x300..], &cmsghdr);
+        remote::put(
+            &mut staging[0x300 + std::mem::size_of::<libc::cmsghdr>()..],
+            &raw_uffd,
+        );
 
-        write_to_staging(
-            unsafe { std::mem::transmute(&msghdr) },
-            std::mem::size_of_val(&msghdr),
-        )?;
+        remote::write(pid, staging_area, &staging)?;
 
         let ret = invoke(libc::SYS_sendmsg, &[sock_fd as _, staging_area as _, 0])? as i32;
         if ret < 0 {
@@ -905,14 +1604,46 @@ impl Tracee {
         debug!("brk(0) returned {end_brk}");
 
         // at this point we should've received the uffd from the other thread.
-        let uffd = accept_jh.join().unwrap();
+        let Some(uffd) = accept_jh.join().unwrap() else {
+            tx.send(MeviEvent::TraceeEvent(
+                tid,
+                TraceePayload::Degraded {
+                    reason: "mevi couldn't watch its userfaultfd, its memory isn't tracked"
+                        .to_owned(),
+                },
+            ))?;
+            self.kind = TraceeKind::Untracked;
+            ptrace::setregs(pid, saved_regs)?;
+            return Ok(());
+        };
 
         // now's a good time to register all the ranges that are R+W, private and anonymous.
+        let mut registered = RangeSet::new();
+        // locked before we attached, for `munlockall`
+        let mut locked = RangeSet::new();
         let p = procfs::process::Process::new(tid.0 as _)?;
+
+        // forked from a process we trace: its memory starts out shared
+        // copy-on-write with its parent's
+        let parent = self
+            .forked_from
+            .filter(|parent| !self.execed && uffds.is_watched(*parent));
+        let forked = parent.is_some();
+        if let Some(parent) = parent {
+            info!("{tid} forked from {parent}");
+            uffds.link(parent, tid);
+            tx.send(MeviEvent::TraceeEvent(
+                tid,
+                TraceePayload::Forked { parent },
+            ))?;
+        }
+
         if let Some(start_brk) = p.stat()?.start_brk {
             if end_brk > start_brk {
                 // FIXME: only accept EBUSY
-                _ = uffd.register(start_brk as _, (end_brk - start_brk) as _);
+                if register(&uffd, &(start_brk..end_brk), Backing::Anon, req_features).is_ok() {
+                    registered.insert(start_brk..end_brk);
+                }
 
                 let formatter = make_format(BINARY);
                 tracing::info!(
@@ -927,8 +1658,20 @@ impl Tracee {
             }
         }
 
-        let maps = p.maps()?;
+        // smaps rather than maps, to tell hugetlb mappings apart
+        let maps = p.smaps()?;
+        let mut scanner = Scanner::new();
+        let mut mappings = MappingMap::new();
         for map in maps {
+            // we say what every mapping is, even those we don't track
+            let range = map.address.0..map.address.1;
+            let mapping = preexisting_mapping(&map, scanner.sees_thps());
+            mappings.insert(range.clone(), mapping.clone());
+            tx.send(MeviEvent::TraceeEvent(
+                tid,
+                TraceePayload::Mapped { range, mapping },
+            ))?;
+
             if !map.perms.contains(
                 MMPermissions::READ | MMPermissions::WRITE, /* | MMPermissions::PRIVATE */
             ) {
@@ -943,19 +1686,34 @@ impl Tracee {
             //     continue;
             // }
 
-            match &map.pathname {
-                MMapPath::Heap | MMapPath::Anonymous => {
-                    // yes, good
+            let shared = map.perms.contains(MMPermissions::SHARED);
+            let backing = match &map.pathname {
+                _ if map.extension.vm_flags.contains(VmFlags::HT) => {
+                    let page_size = map.extension.map.get("KernelPageSize").copied();
+                    match page_size {
+                        Some(page_size) => Backing::Hugetlb { page_size },
+                        None => {
+                            info!("{tid} skipping over hugetlb mapping {:?}", map.pathname);
+                            continue;
+                        }
+                    }
                 }
+                MMapPath::Heap | MMapPath::Anonymous => Backing::Anon,
                 MMapPath::Path(p) => {
-                    // continue only if it does not start with /dev/shm
-                    if !p.starts_with("/dev/shm") {
+                    // continue only if it's shared memory of some sort
+                    if !is_shmem_path(p) {
                         info!(
                             "{tid} skipping over pathname {:?} with dev {:?}",
                             map.pathname, map.dev
                         );
                         continue;
                     }
+                    if shared {
+                        Backing::Shmem
+                    } else {
+                        // private mappings of shmem work like anonymous memory
+                        Backing::Anon
+                    }
                 }
                 MMapPath::Stack
                 | MMapPath::TStack(_)
@@ -972,24 +1730,44 @@ impl Tracee {
                     );
                     continue;
                 }
-            }
+            };
 
             let range = map.address.0..map.address.1;
-            info!("{tid} has stuff at {range:x?} with perms {:?}", map.perms);
+            info!(
+                "{tid} has stuff at {range:x?} with perms {:?}, backed by {backing:?}",
+                map.perms
+            );
 
-            tx.send(MeviEvent::TraceeEvent(
+            uffds.send(
                 tid,
                 TraceePayload::MemStateChange {
                     range: range.clone(),
                     state: MemState::Untracked,
                 },
-            ))
-            .unwrap();
+            );
 
-            if let Err(e) = uffd.register(
-                range.start as _,
-                (range.end.checked_sub(range.start).unwrap()) as _,
-            ) {
+            // huge page hints and locks from before we attached
+            for (flag, attr) in [
+                (VmFlags::HG, Attribute::HugePages(HugePageAdvice::Huge)),
+                (VmFlags::NH, Attribute::HugePages(HugePageAdvice::NoHuge)),
+                (VmFlags::LO, Attribute::Locked(true)),
+            ] {
+                if map.extension.vm_flags.contains(flag) {
+                    if flag == VmFlags::LO {
+                        locked.insert(range.clone());
+                    }
+                    tx.send(MeviEvent::TraceeEvent(
+                        tid,
+                        TraceePayload::SetAttribute {
+                            range: range.clone(),
+                            attr,
+                        },
+                    ))?;
+                }
+            }
+
+            uffds.set_backing(tid, range.clone(), backing);
+            if let Err(e) = register(&uffd, &range, backing, req_features) {
                 match e {
                     userfaultfd::Error::SystemError(e) => {
                         if e == nix::Error::EBUSY {
@@ -1003,48 +1781,27 @@ impl Tracee {
                     other => return Err(other.into()),
                 }
             }
-
-            let mut num_pages = 0;
-            let mut num_present_pages = 0;
-
-            let page_size = nix::unistd::sysconf(SysconfVar::PAGE_SIZE)?.unwrap() as u64;
-            let start_idx = (map.address.0 / page_size) as usize;
-            let end_idx = (map.address.1 / page_size) as usize;
-            let mut pm = p.pagemap()?;
-            for (rel_idx, pi) in pm
-                .get_range_info(start_idx..end_idx)?
-                .into_iter()
-                .enumerate()
-            {
-                let addr = map.address.0 + rel_idx as u64 * page_size;
-                if let PageInfo::MemoryPage(mp) = pi {
-                    tracing::debug!("{tid} {addr:x?} = {pi:?}",);
-                    num_pages += 1;
-                    if mp.contains(MemoryPageFlags::PRESENT) {
-                        num_present_pages += 1;
+            registered.insert(range.clone());
+
+            let mut num_present = 0;
+            let mut num_huge = 0;
+            for (range, state) in scanner.scan(&p, range.clone(), backing, forked)? {
+                match state {
+                    MemState::Resident | MemState::SharedCow => {
+                        num_present += range.end - range.start
                     }
-
-                    // TODO: we can do a lot fewer events here by coalescing those
-                    // ranges but let's worry about that later
-                    tx.send(MeviEvent::TraceeEvent(
-                        tid,
-                        TraceePayload::MemStateChange {
-                            range: addr..addr + page_size,
-                            state: if mp.contains(MemoryPageFlags::PRESENT) {
-                                MemState::Resident
-                            } else {
-                                MemState::NotResident
-                            },
-                        },
-                    ))
-                    .unwrap();
-                } else {
-                    panic!("expected a MemoryPage PageInfo");
+                    MemState::HugeResident => num_huge += range.end - range.start,
+                    _ => {}
                 }
+                uffds.send(tid, TraceePayload::MemStateChange { range, state });
             }
 
+            let formatter = make_format(BINARY);
             tracing::info!(
-                "{tid} that range had {num_present_pages} present pages out of {num_pages}"
+                "{tid} that range had {} present, {} of it in huge pages, out of {}",
+                formatter(num_present + num_huge),
+                formatter(num_huge),
+                formatter(range.end - range.start),
             );
         }
 
@@ -1056,17 +1813,34 @@ impl Tracee {
             TraceePayload::CmdLineChange { cmdline },
         ))?;
 
+        if self.user_faults_only {
+            tx.send(MeviEvent::TraceeEvent(
+                tid,
+                TraceePayload::Degraded {
+                    reason: "only user faults are tracked, syscalls touching fresh memory fail with EFAULT".to_owned(),
+                },
+            ))?;
+        }
+
         self.kind = TraceeKind::Process {
             heap_range: end_brk..end_brk,
             uffd,
+            registered,
+            features: req_features,
+            lock_future: None,
+            locked,
+            mappings,
         };
         ptrace::setregs(pid, saved_regs)?;
+        info!("{tid} connected in {:?}", started.elapsed());
 
         Ok(())
     }
 }
 
-fn receive_uffd(mut tx: mpsc::SyncSender<MeviEvent>, listener: &UnixListener) -> Uffd {
+/// Gets the uffd a tracee sends us, and hands a copy to the event loop.
+/// `None` if the event loop can't watch it.
+fn receive_uffd(uffds: &UffdLoop, listener: &UnixListener) -> Option<Uffd> {
     let (mut stream, addr) = listener.accept().unwrap();
     debug!("accepted unix stream from {addr:?}!");
 
@@ -1085,11 +1859,199 @@ fn receive_uffd(mut tx: mpsc::SyncSender<MeviEvent>, listener: &UnixListener) ->
     let uffd = unsafe { Uffd::from_raw_fd(uffd_raw) };
     debug!("{tid} sent us uffd {}", uffd.as_raw_fd());
 
-    std::thread::spawn(move || {
-        crate::userfault::handle(&mut tx, tid, uffd);
-    });
+    // the event loop and the tracer each get their own fd, so that either
+    // can close theirs whenever.
+    let dup_raw = match nix::unistd::dup(uffd_raw) {
+        Ok(fd) => fd,
+        Err(e) => {
+            warn!("{tid} couldn't duplicate uffd {uffd_raw}: {e}");
+            return None;
+        }
+    };
+    let dup = unsafe { Uffd::from_raw_fd(dup_raw) };
+    if let Err(e) = uffds.add(tid, uffd) {
+        warn!("{tid} couldn't watch uffd {uffd_raw}: {e}");
+        return None;
+    }
+    Some(dup)
+}
+
+/// Registers `range` in every mode `backing` and the negotiated `features`
+/// allow: missing faults, minor faults for shared memory and hugetlbfs, and
+/// write-protect faults for anonymous memory if we're tracking writes. The
+/// kernel may still refuse some of those for a given mapping, so they're
+/// dropped one by one until it's happy.
+fn register(
+    uffd: &Uffd,
+    range: &Range<u64>,
+    backing: Backing,
+    features: FeatureFlags,
+) -> userfaultfd::Result<IoctlFlags> {
+    let start = range.start as _;
+    let len = (range.end.checked_sub(range.start).unwrap()) as _;
+
+    let mut mode = match backing {
+        Backing::Anon if features.contains(FeatureFlags::PAGEFAULT_FLAG_WP) => {
+            RegisterMode::MISSING | RegisterMode::WRITE_PROTECT
+        }
+        Backing::Anon => RegisterMode::MISSING,
+        Backing::Shmem if features.contains(FeatureFlags::MINOR_SHMEM) => {
+            RegisterMode::MISSING | RegisterMode::MINOR
+        }
+        Backing::Shmem => RegisterMode::MISSING,
+        Backing::Hugetlb { .. } if features.contains(FeatureFlags::MINOR_HUGETLBFS) => {
+            RegisterMode::MISSING | RegisterMode::MINOR
+        }
+        Backing::Hugetlb { .. } => RegisterMode::MISSING,
+    };
+    loop {
+        match uffd.register_with_mode(start, len, mode) {
+            Err(userfaultfd::Error::SystemError(Errno::EINVAL))
+                if mode.contains(RegisterMode::MISSING) && mode != RegisterMode::MISSING =>
+            {
+                debug!("can't register {range:x?} with {mode:?}, falling back to missing faults");
+                mode = RegisterMode::MISSING;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// What's behind a fresh read-write mapping, if it's something we track
+fn mmap_backing(tid: TraceeId, map_flags: MapFlags, raw_flags: u64, fd: i32) -> Option<Backing> {
+    let shared = map_flags.contains(MapFlags::MAP_SHARED);
+    if map_flags.contains(MapFlags::MAP_ANONYMOUS) {
+        if map_flags.contains(MapFlags::MAP_HUGETLB) {
+            // the page size is log2-encoded in the flags, 0 is the default
+            let shift = (raw_flags >> libc::MAP_HUGE_SHIFT) & libc::MAP_HUGE_MASK as u64;
+            let page_size = if shift == 0 {
+                procfs::Meminfo::new().ok()?.hugepagesize?
+            } else {
+                1 << shift
+            };
+            return Some(Backing::Hugetlb { page_size });
+        }
+        return Some(if shared {
+            Backing::Shmem
+        } else {
+            Backing::Anon
+        });
+    }
+
+    if !shared || fd < 0 {
+        return None;
+    }
+    // the fd is still open in the tracee, look at what filesystem it's on:
+    // that covers /dev/shm, memfd, and hugetlbfs.
+    let fs = statfs(format!("/proc/{}/fd/{fd}", tid.0).as_str()).ok()?;
+    match fs.filesystem_type() {
+        TMPFS_MAGIC => Some(Backing::Shmem),
+        HUGETLBFS_MAGIC => Some(Backing::Hugetlb {
+            page_size: fs.block_size() as _,
+        }),
+        _ => None,
+    }
+}
+
+/// Whether a mapping's path in `/proc/pid/maps` is some sort of shared memory
+fn is_shmem_path(path: &std::path::Path) -> bool {
+    let path = path.to_string_lossy();
+    // memfd, POSIX shm, shared anonymous mappings, System V shm
+    ["/memfd:", "/dev/shm/", "/dev/zero", "/SYSV"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
 
-    unsafe { Uffd::from_raw_fd(uffd_raw) }
+fn protection(prot: ProtFlags) -> Protection {
+    Protection {
+        read: prot.contains(ProtFlags::PROT_READ),
+        write: prot.contains(ProtFlags::PROT_WRITE),
+        exec: prot.contains(ProtFlags::PROT_EXEC),
+    }
+}
+
+/// The names of `mmap` flags, as they'd be written in C
+fn map_flag_names(flags: u64) -> Vec<String> {
+    let flags = flags as libc::c_int;
+    let mut names = vec![];
+    match flags & libc::MAP_TYPE {
+        libc::MAP_SHARED => names.push("MAP_SHARED"),
+        libc::MAP_PRIVATE => names.push("MAP_PRIVATE"),
+        libc::MAP_SHARED_VALIDATE => names.push("MAP_SHARED_VALIDATE"),
+        _ => {}
+    }
+    for (flag, name) in [
+        (libc::MAP_FIXED, "MAP_FIXED"),
+        (libc::MAP_FIXED_NOREPLACE, "MAP_FIXED_NOREPLACE"),
+        (libc::MAP_ANONYMOUS, "MAP_ANONYMOUS"),
+        (libc::MAP_GROWSDOWN, "MAP_GROWSDOWN"),
+        (libc::MAP_LOCKED, "MAP_LOCKED"),
+        (libc::MAP_NORESERVE, "MAP_NORESERVE"),
+        (libc::MAP_POPULATE, "MAP_POPULATE"),
+        (libc::MAP_NONBLOCK, "MAP_NONBLOCK"),
+        (libc::MAP_STACK, "MAP_STACK"),
+        (libc::MAP_HUGETLB, "MAP_HUGETLB"),
+        (libc::MAP_SYNC, "MAP_SYNC"),
+    ] {
+        if flags & flag == flag {
+            names.push(name);
+        }
+    }
+    names.into_iter().map(String::from).collect()
+}
+
+/// What a tracked `mmap` is backed by, from the file behind `fd` if any
+fn mmap_mapping_backing(tid: TraceeId, map_flags: MapFlags, fd: i32) -> MappingBacking {
+    let shared = map_flags.contains(MapFlags::MAP_SHARED);
+    if map_flags.contains(MapFlags::MAP_ANONYMOUS) {
+        return if shared {
+            MappingBacking::Shm(None)
+        } else if map_flags.contains(MapFlags::MAP_STACK) {
+            MappingBacking::Stack
+        } else {
+            MappingBacking::Anon
+        };
+    }
+    let path = std::fs::read_link(format!("/proc/{}/fd/{fd}", tid.0))
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| format!("fd {fd}"));
+    if shared {
+        MappingBacking::Shm(Some(path))
+    } else {
+        MappingBacking::File(path)
+    }
+}
+
+/// What a mapping found in `/proc/PID/smaps` is, as far as we can tell
+fn preexisting_mapping(map: &MemoryMap, sees_thps: bool) -> Mapping {
+    let shared = map.perms.contains(MMPermissions::SHARED);
+    let backing = match &map.pathname {
+        MMapPath::Heap => MappingBacking::Heap,
+        MMapPath::Stack | MMapPath::TStack(_) => MappingBacking::Stack,
+        MMapPath::Anonymous if shared => MappingBacking::Shm(None),
+        MMapPath::Anonymous => MappingBacking::Anon,
+        path if shared => MappingBacking::Shm(Some(mapping_name(path))),
+        path => MappingBacking::File(mapping_name(path)),
+    };
+    Mapping {
+        prot: Protection {
+            read: map.perms.contains(MMPermissions::READ),
+            write: map.perms.contains(MMPermissions::WRITE),
+            exec: map.perms.contains(MMPermissions::EXECUTE),
+        },
+        flags: vec![if shared { "MAP_SHARED" } else { "MAP_PRIVATE" }.to_string()],
+        backing,
+        origin: MappingOrigin::Preexisting,
+        unplaced_huge_bytes: if sees_thps { 0 } else { huge_bytes(map) },
+    }
+}
+
+/// How much of a mapping is in transparent huge pages, from smaps
+fn huge_bytes(map: &MemoryMap) -> u64 {
+    ["AnonHugePages", "ShmemPmdMapped"]
+        .iter()
+        .filter_map(|key| map.extension.map.get(*key))
+        .sum()
 }
 
 fn get_cmdline(tid: TraceeId) -> Vec<String> {
@@ -1100,3 +2062,25 @@ fn get_cmdline(tid: TraceeId) -> Vec<String> {
         .map(|s| s.to_owned())
         .collect()
 }
+
+/// The pages a syscall like `mmap` or `mlock` applies to: `addr` is rounded
+/// down, and `addr + len` up, to a page boundary.
+fn page_range(addr: u64, len: u64) -> Range<u64> {
+    let page_size = procfs::page_size();
+    let start = addr & !(page_size - 1);
+    let end = (addr + len).next_multiple_of(page_size);
+    start..end
+}
+
+/// The process a pidfd of `tid` refers to, from `/proc/TID/fdinfo`.
+fn pidfd_target(tid: TraceeId, fd: i32) -> Option<TraceeId> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/{}/fdinfo/{fd}", tid.0)).ok()?;
+    let pid: i64 = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))?
+        .trim()
+        .parse()
+        .ok()?;
+    // -1 once the process is gone, 0 if it's in another pid namespace
+    (pid > 0).then_some(TraceeId(pid as u64))
+}
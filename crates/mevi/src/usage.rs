@@ -0,0 +1,65 @@
+use std::{collections::HashMap, sync::mpsc};
+
+use mevi_common::{MeviEvent, TraceeId, TraceePayload, Usage};
+use tracing::debug;
+
+/// Every tracee we want PSS and USS for
+type Job = Vec<TraceeId>;
+
+/// Reads what the kernel says tracees' memory usage is, from
+/// `/proc/PID/smaps_rollup`.
+///
+/// That walks every page table of the process, so it's kept off the relay
+/// thread.
+#[derive(Clone)]
+pub(crate) struct UsageSampler {
+    jobs: mpsc::SyncSender<Job>,
+}
+
+impl UsageSampler {
+    pub(crate) fn spawn(tx: mpsc::SyncSender<MeviEvent>) -> Self {
+        let (jobs, jobs_rx) = mpsc::sync_channel::<Job>(1);
+        std::thread::spawn(move || {
+            let mut last: HashMap<TraceeId, Usage> = Default::default();
+            for job in jobs_rx {
+                last.retain(|tid, _| job.contains(tid));
+                for tid in job {
+                    let Some(usage) = sample(tid) else {
+                        continue;
+                    };
+                    if last.get(&tid) == Some(&usage) {
+                        continue;
+                    }
+                    last.insert(tid, usage);
+                    let payload = TraceePayload::Usage(usage);
+                    if tx.send(MeviEvent::TraceeEvent(tid, payload)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Self { jobs }
+    }
+
+    /// Has usage sampled, unless the previous job is still going.
+    pub(crate) fn request(&self, job: Job) {
+        if self.jobs.try_send(job).is_err() {
+            debug!("usage sampler still running, skipping this one");
+        }
+    }
+}
+
+/// RSS, PSS and USS of `tid`, as the kernel counts them
+fn sample(tid: TraceeId) -> Option<Usage> {
+    // fails if it's exiting, or the kernel is older than 4.14
+    let rollup = procfs::process::Process::new(tid.0 as _)
+        .and_then(|p| p.smaps_rollup())
+        .ok()?;
+    let map = rollup.memory_map_rollup.memory_maps.first()?;
+    let field = |key: &str| map.extension.map.get(key).copied().unwrap_or_default();
+    Some(Usage {
+        rss: field("Rss"),
+        pss: field("Pss"),
+        uss: field("Private_Clean") + field("Private_Dirty"),
+    })
+}
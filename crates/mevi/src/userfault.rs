@@ -1,107 +1,1236 @@
-use std::{os::fd::AsRawFd, sync::mpsc};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use humansize::{make_format, BINARY};
-use mevi_common::{MemState, MeviEvent, TraceeId, TraceePayload};
-use nix::unistd::{sysconf, SysconfVar};
-use tracing::{debug, warn};
-use userfaultfd::Uffd;
+use mevi_common::{MemMap, MemState, MeviEvent, TraceeId, TraceePayload, WorkingSet};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
+    unistd::{sysconf, SysconfVar},
+};
+use procfs::{process::Process, ProcResult};
+use rangemap::{RangeMap, RangeSet};
+use tracing::{debug, info, warn};
+use userfaultfd::{raw, EventBuffer, FaultKind, FeatureFlags, ReadWrite, ThreadId, Uffd};
 
-pub(crate) fn handle(tx: &mut mpsc::SyncSender<MeviEvent>, tid: TraceeId, uffd: Uffd) {
-    let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as u64;
+use crate::{
+    content::{mapping_name, per_mapping},
+    options::Options,
+    pagemap::Scanner,
+};
 
-    let send_ev = |payload: TraceePayload| {
-        tx.send(MeviEvent::TraceeEvent(tid, payload)).unwrap();
+// from linux/userfaultfd.h, Linux 5.11+
+pub(crate) const UFFD_USER_MODE_ONLY: u64 = 1;
+
+/// How many uffd messages we read in one go
+const EVENT_BATCH: usize = 64;
+
+/// How often we sample soft-dirty bits, with `--working-set`
+const WORKING_SET_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often we check which pages forked processes still share
+/// copy-on-write, since copying them on write doesn't go through uffd
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The features we only ask for if the kernel has them
+const OPTIONAL_FEATURES: FeatureFlags =
+    FeatureFlags::MINOR_SHMEM.union(FeatureFlags::MINOR_HUGETLBFS);
+
+/// What's behind a range of memory, which decides how it's registered and
+/// how its faults are resolved.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Backing {
+    /// Private anonymous memory, including the heap
+    Anon,
+    /// Shared memory (/dev/shm, memfd, shared anonymous mappings). Its pages
+    /// may already be in the page cache, in which case touching them is a
+    /// minor fault rather than a missing one.
+    Shmem,
+    /// hugetlbfs mappings and MAP_HUGETLB, faulted in one huge page at a
+    /// time. They can't be zero-filled like the others, so missing pages are
+    /// copied in from a zeroed buffer.
+    Hugetlb { page_size: u64 },
+}
+
+/// A process's uffd, and what we know about the memory registered with it
+struct Watched {
+    uffd: Uffd,
+    /// Anything that's not in there is [Backing::Anon]
+    backings: RangeMap<u64, Backing>,
+    /// What we last reported for registered memory, which the reconciler
+    /// checks against pagemap.
+    reported: MemMap,
+    /// When pages were last seen written to, with `--working-set`
+    written: RangeMap<u64, Instant>,
+    /// The working set we last reported
+    working_set: Option<WorkingSet>,
+    /// Traced processes it forked from or into, that haven't exited or
+    /// exec'd since: its memory may be shared copy-on-write with theirs.
+    relatives: HashSet<TraceeId>,
+    /// Threads we've seen fault, that are the tracee's own and haven't exited
+    threads: HashSet<TraceeId>,
+}
+
+fn backing_at(backings: &RangeMap<u64, Backing>, addr: u64) -> Backing {
+    backings.get(&addr).copied().unwrap_or(Backing::Anon)
+}
+
+/// Handles the page faults and events of every tracee's uffd from a single
+/// thread, so we don't need one thread per process.
+#[derive(Clone)]
+pub(crate) struct UffdLoop {
+    tx: mpsc::SyncSender<MeviEvent>,
+    epoll: Arc<Epoll>,
+    uffds: Arc<Mutex<HashMap<TraceeId, Watched>>>,
+    /// Which of [OPTIONAL_FEATURES] this kernel supports
+    optional_features: FeatureFlags,
+    /// How many pages to fill in per fault
+    fault_around: u64,
+    track_writes: bool,
+    /// How often to check registered memory against pagemap
+    reconcile_interval: Option<Duration>,
+    /// How far back the working set goes, if we're estimating it
+    working_set_window: Option<Duration>,
+    /// Tracees whose resident memory may have started (or stopped) being
+    /// shared copy-on-write, to check against pagemap
+    shared_checks: mpsc::Sender<TraceeId>,
+}
+
+impl UffdLoop {
+    pub(crate) fn spawn(tx: mpsc::SyncSender<MeviEvent>, opts: &Options) -> nix::Result<Self> {
+        let optional_features = supported_features() & OPTIONAL_FEATURES;
+        info!("optional uffd features supported by this kernel: {optional_features:?}");
+        let (shared_checks, shared_checks_rx) = mpsc::channel();
+
+        let uffd_loop = Self {
+            tx,
+            epoll: Arc::new(Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?),
+            uffds: Default::default(),
+            optional_features,
+            fault_around: opts.fault_around,
+            track_writes: opts.track_writes,
+            reconcile_interval: opts.reconcile_interval,
+            working_set_window: opts.working_set_window,
+            shared_checks,
+        };
+        std::thread::spawn({
+            let uffd_loop = uffd_loop.clone();
+            move || uffd_loop.run()
+        });
+        if let Some(interval) = uffd_loop.reconcile_interval {
+            uffd_loop.spawn_reconciler(interval);
+        }
+        if let Some(window) = uffd_loop.working_set_window {
+            uffd_loop.spawn_working_set_sampler(window);
+        }
+        uffd_loop.spawn_rechecker(shared_checks_rx);
+        Ok(uffd_loop)
+    }
+
+    /// Starts handling `uffd`'s events. It's switched to non-blocking mode,
+    /// which is shared with any other fd for the same uffd: only this loop
+    /// should read events from it.
+    pub(crate) fn add(&self, tid: TraceeId, uffd: Uffd) -> nix::Result<()> {
+        let flags = OFlag::from_bits_truncate(fcntl(uffd.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            uffd.as_raw_fd(),
+            FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+        )?;
+
+        let mut uffds = self.uffds.lock().unwrap();
+        self.epoll
+            .add(borrow(&uffd), EpollEvent::new(EpollFlags::EPOLLIN, tid.0))?;
+        let watched = Watched {
+            uffd,
+            backings: Default::default(),
+            reported: Default::default(),
+            written: Default::default(),
+            working_set: Default::default(),
+            relatives: Default::default(),
+            threads: Default::default(),
+        };
+        if let Some(old) = uffds.insert(tid, watched) {
+            // we missed an exec, or the pid got reused
+            warn!(
+                "{tid} already had uffd {}, replacing it",
+                old.uffd.as_raw_fd()
+            );
+            _ = self.epoll.delete(borrow(&old.uffd));
+            self.unlink(&mut uffds, tid);
+        }
+        Ok(())
+    }
+
+    /// Stops handling events for `tid`, and closes our copy of its uffd.
+    /// Also for processes that exit or exec before they have a uffd, so
+    /// they stop counting as anyone's relative.
+    pub(crate) fn remove(&self, tid: TraceeId) {
+        let mut uffds = self.uffds.lock().unwrap();
+        if let Some(watched) = uffds.remove(&tid) {
+            debug!("{tid} no longer watching uffd {}", watched.uffd.as_raw_fd());
+            _ = self.epoll.delete(borrow(&watched.uffd));
+        }
+        self.unlink(&mut uffds, tid);
+    }
+
+    /// Forgets `thread` of `tid` once it exited: its id may get reused, by a
+    /// thread of another process even.
+    pub(crate) fn forget_thread(&self, tid: TraceeId, thread: TraceeId) {
+        if let Some(watched) = self.uffds.lock().unwrap().get_mut(&tid) {
+            watched.threads.remove(&thread);
+        }
+    }
+
+    /// Forgets `tid` as a relative of other tracees, now that it's gone or
+    /// exec'd. Those left without any get their shared pages checked again,
+    /// as they aren't shared anymore.
+    fn unlink(&self, uffds: &mut HashMap<TraceeId, Watched>, tid: TraceeId) {
+        for (other, watched) in uffds.iter_mut() {
+            if watched.relatives.remove(&tid) && watched.relatives.is_empty() {
+                _ = self.shared_checks.send(*other);
+            }
+        }
+    }
+
+    /// Features to ask for on top of the ones we always need, if the kernel
+    /// supports them.
+    pub(crate) fn optional_features(&self) -> FeatureFlags {
+        self.optional_features
+    }
+
+    /// Records what's behind `range`, before it's registered so its first
+    /// faults are handled correctly.
+    pub(crate) fn set_backing(&self, tid: TraceeId, range: Range<u64>, backing: Backing) {
+        if let Some(watched) = self.uffds.lock().unwrap().get_mut(&tid) {
+            match backing {
+                Backing::Anon => watched.backings.remove(range),
+                _ => watched.backings.insert(range, backing),
+            }
+        }
+    }
+
+    /// Reports a change to registered memory that the tracer found out
+    /// about, so the reconciler knows about it too.
+    pub(crate) fn send(&self, tid: TraceeId, payload: TraceePayload) {
+        if let Some(watched) = self.uffds.lock().unwrap().get_mut(&tid) {
+            payload.apply_to_memmap(&mut watched.reported);
+        }
+        self.tx.send(MeviEvent::TraceeEvent(tid, payload)).unwrap();
+    }
+
+    /// Whether we're handling `tid`'s uffd
+    pub(crate) fn is_watched(&self, tid: TraceeId) -> bool {
+        self.uffds.lock().unwrap().contains_key(&tid)
+    }
+
+    /// Records that `child` forked from `parent`, so their pages show up as
+    /// shared copy-on-write until either of them writes to them. The child
+    /// may not have a uffd yet, in which case it's linked again once it has.
+    ///
+    /// The parent's memory is checked against pagemap the first time, off
+    /// the calling thread.
+    pub(crate) fn link(&self, parent: TraceeId, child: TraceeId) {
+        let mut uffds = self.uffds.lock().unwrap();
+        let mut new = false;
+        if let Some(watched) = uffds.get_mut(&parent) {
+            new = watched.relatives.insert(child);
+        }
+        if let Some(watched) = uffds.get_mut(&child) {
+            watched.relatives.insert(parent);
+        }
+        if new {
+            _ = self.shared_checks.send(parent);
+        }
+    }
+
+    /// Marks what's resident in `range` as lazily freed. `MADV_FREE` doesn't
+    /// generate any uffd event, and leaves non-resident pages alone.
+    pub(crate) fn lazy_free(&self, tid: TraceeId, range: Range<u64>) {
+        let mut uffds = self.uffds.lock().unwrap();
+        let Some(watched) = uffds.get_mut(&tid) else {
+            return;
+        };
+        let freed: Vec<_> = watched
+            .reported
+            .overlapping(&range)
+            .filter(|(_, state)| state.is_resident())
+            .map(|(r, _)| r.start.max(range.start)..r.end.min(range.end))
+            .collect();
+        let mut payloads = vec![];
+        for range in freed {
+            let payload = TraceePayload::MemStateChange {
+                range,
+                state: MemState::LazyFree,
+            };
+            payload.apply_to_memmap(&mut watched.reported);
+            payloads.push(payload);
+        }
+        drop(uffds);
+        self.send_all(tid, payloads);
+    }
+
+    /// Marks all of `tid`'s tracked memory as not resident, after
+    /// `process_mrelease` freed it.
+    pub(crate) fn release(&self, tid: TraceeId) {
+        let mut uffds = self.uffds.lock().unwrap();
+        let Some(watched) = uffds.get_mut(&tid) else {
+            return;
+        };
+        let released: Vec<_> = watched
+            .reported
+            .iter()
+            .filter(|(_, state)| {
+                state.is_resident()
+                    || matches!(
+                        state,
+                        MemState::Swapped | MemState::Prefaulted | MemState::ZeroPage
+                    )
+            })
+            .map(|(range, _)| range.clone())
+            .collect();
+        let mut payloads = vec![];
+        for range in released {
+            let payload = TraceePayload::MemStateChange {
+                range,
+                state: MemState::NotResident,
+            };
+            payload.apply_to_memmap(&mut watched.reported);
+            payloads.push(payload);
+        }
+        drop(uffds);
+        self.send_all(tid, payloads);
+    }
+
+    /// Sends what was applied to `reported` to the relay. Never with the
+    /// `uffds` lock held: the relay's channel is bounded, and page faults
+    /// wait on that lock.
+    fn send_all(&self, tid: TraceeId, payloads: Vec<TraceePayload>) {
+        for payload in payloads {
+            self.tx.send(MeviEvent::TraceeEvent(tid, payload)).unwrap();
+        }
+    }
+
+    /// Checks `tid`'s memory against pagemap right away, for when it's
+    /// likely to have changed without any page faults.
+    pub(crate) fn reconcile_now(&self, tid: TraceeId) {
+        if let Err(e) = self.reconcile(tid, &mut Scanner::new(), None) {
+            // it probably exited, we'll hear about it
+            debug!("{tid} couldn't reconcile: {e}");
+        }
+    }
+
+    /// Like [UffdLoop::reconcile_now], but only for the pages in `ranges`.
+    pub(crate) fn recheck(&self, tid: TraceeId, ranges: &[Range<u64>]) {
+        if let Err(e) = self.reconcile(tid, &mut Scanner::new(), Some(ranges)) {
+            debug!("{tid} couldn't recheck {ranges:x?}: {e}");
+        }
+    }
+
+    /// Checks what we reported for `tid`'s memory against pagemap, and
+    /// reports the difference. That's pages that got swapped out, reclaimed,
+    /// swapped back in, merged into (or split from) THPs, or stopped being
+    /// shared copy-on-write, none of which go through uffd.
+    ///
+    /// Pagemap is read without holding the lock, so faults keep getting
+    /// handled. Corrections only apply to what we haven't reported anything
+    /// new for since.
+    ///
+    /// Only the pages `within` those ranges are checked, if any.
+    fn reconcile(
+        &self,
+        tid: TraceeId,
+        scanner: &mut Scanner,
+        within: Option<&[Range<u64>]>,
+    ) -> ProcResult<()> {
+        let (reported, stretches, forked) = {
+            let uffds = self.uffds.lock().unwrap();
+            let Some(watched) = uffds.get(&tid) else {
+                return Ok(());
+            };
+            let mut stretches = tracked_stretches(watched);
+            let reported: MemMap = match within {
+                None => watched.reported.clone(),
+                Some(within) => {
+                    let by_range: RangeMap<u64, Backing> = stretches.into_iter().collect();
+                    stretches = within
+                        .iter()
+                        .flat_map(|range| {
+                            by_range.overlapping(range).map(|(stretch, backing)| {
+                                let start = stretch.start.max(range.start);
+                                let end = stretch.end.min(range.end);
+                                (start..end, *backing)
+                            })
+                        })
+                        .collect();
+                    within
+                        .iter()
+                        .flat_map(|range| watched.reported.overlapping(range))
+                        .map(|(range, state)| (range.clone(), *state))
+                        .collect()
+                }
+            };
+            (reported, stretches, !watched.relatives.is_empty())
+        };
+
+        let p = Process::new(tid.0 as _)?;
+        let mut corrections: Vec<(Range<u64>, MemState, MemState)> = vec![];
+        for (stretch, backing) in stretches {
+            for (range, actual) in scanner.scan(&p, stretch, backing, forked)? {
+                for (known_range, known) in reported.overlapping(&range) {
+                    let Some(state) = correction(*known, actual, scanner.sees_thps()) else {
+                        continue;
+                    };
+                    let range = known_range.start.max(range.start)..known_range.end.min(range.end);
+                    match corrections.last_mut() {
+                        Some((last, last_known, last_state))
+                            if last.end == range.start
+                                && *last_known == *known
+                                && *last_state == state =>
+                        {
+                            last.end = range.end
+                        }
+                        _ => corrections.push((range, *known, state)),
+                    }
+                }
+            }
+        }
+        if corrections.is_empty() {
+            return Ok(());
+        }
+
+        let mut uffds = self.uffds.lock().unwrap();
+        let Some(watched) = uffds.get_mut(&tid) else {
+            return Ok(());
+        };
+        let mut payloads = vec![];
+        for (range, known, state) in corrections {
+            // faults and events that came in while we were scanning know better
+            let unchanged: Vec<_> = watched
+                .reported
+                .overlapping(&range)
+                .filter(|(_, current)| **current == known)
+                .map(|(r, _)| r.start.max(range.start)..r.end.min(range.end))
+                .collect();
+            for range in unchanged {
+                let payload = TraceePayload::MemStateChange { range, state };
+                payload.apply_to_memmap(&mut watched.reported);
+                payloads.push(payload);
+            }
+        }
+        drop(uffds);
+        debug!("{tid} reconciled {} ranges", payloads.len());
+        self.send_all(tid, payloads);
+        Ok(())
+    }
+
+    /// Checks every tracee's memory against pagemap every `interval`, on its
+    /// own thread.
+    fn spawn_reconciler(&self, interval: Duration) {
+        let uffd_loop = self.clone();
+        std::thread::spawn(move || {
+            let mut scanner = Scanner::new();
+            loop {
+                std::thread::sleep(interval);
+                let tids: Vec<TraceeId> = uffd_loop.uffds.lock().unwrap().keys().copied().collect();
+                for tid in tids {
+                    if let Err(e) = uffd_loop.reconcile(tid, &mut scanner, None) {
+                        debug!("{tid} couldn't reconcile: {e}");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Checks the resident memory of tracees sent to `checks` against
+    /// pagemap, and every [RECHECK_INTERVAL], which lazily freed pages got
+    /// reclaimed and which of the pages forked tracees share copy-on-write
+    /// got copied since. On its own thread, whether or not
+    /// `--reconcile-interval` is on.
+    fn spawn_rechecker(&self, checks: mpsc::Receiver<TraceeId>) {
+        let uffd_loop = self.clone();
+        std::thread::spawn(move || {
+            let mut scanner = Scanner::new();
+            let mut next_recheck = Instant::now() + RECHECK_INTERVAL;
+            loop {
+                match checks.recv_timeout(next_recheck.saturating_duration_since(Instant::now())) {
+                    Ok(tid) => uffd_loop.recheck_where(tid, &mut scanner, MemState::is_resident),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        let tracees: Vec<(TraceeId, bool)> = uffd_loop
+                            .uffds
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .map(|(tid, watched)| (*tid, !watched.relatives.is_empty()))
+                            .collect();
+                        for (tid, forked) in tracees {
+                            uffd_loop.recheck_where(tid, &mut scanner, |state| {
+                                state == MemState::LazyFree
+                                    || (forked && state == MemState::SharedCow)
+                            });
+                        }
+                        next_recheck = Instant::now() + RECHECK_INTERVAL;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+    }
+
+    /// Like [UffdLoop::recheck], for the pages we last reported in a state
+    /// that `which` is true for.
+    fn recheck_where(
+        &self,
+        tid: TraceeId,
+        scanner: &mut Scanner,
+        which: impl Fn(MemState) -> bool,
+    ) {
+        let ranges: Vec<Range<u64>> = {
+            let uffds = self.uffds.lock().unwrap();
+            let Some(watched) = uffds.get(&tid) else {
+                return;
+            };
+            watched
+                .reported
+                .iter()
+                .filter(|(_, state)| which(**state))
+                .map(|(range, _)| range.clone())
+                .collect()
+        };
+        if ranges.is_empty() {
+            return;
+        }
+        if let Err(e) = self.reconcile(tid, scanner, Some(&ranges)) {
+            debug!("{tid} couldn't recheck {} ranges: {e}", ranges.len());
+        }
+    }
+
+    /// Samples every tracee's working set every [WORKING_SET_INTERVAL], on
+    /// its own thread.
+    fn spawn_working_set_sampler(&self, window: Duration) {
+        let uffd_loop = self.clone();
+        std::thread::spawn(move || {
+            let mut scanner = Scanner::new();
+            loop {
+                std::thread::sleep(WORKING_SET_INTERVAL);
+                let tids: Vec<TraceeId> = uffd_loop.uffds.lock().unwrap().keys().copied().collect();
+                for tid in tids {
+                    if let Err(e) = uffd_loop.sample_working_set(tid, &mut scanner, window) {
+                        debug!("{tid} couldn't sample working set: {e}");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Finds out which pages were written to since the last sample, from
+    /// their soft-dirty bit, then clears it for the next one. Reports the
+    /// pages written to within `window` if that changed.
+    ///
+    /// Like [UffdLoop::reconcile], pagemap is read without holding the lock.
+    fn sample_working_set(
+        &self,
+        tid: TraceeId,
+        scanner: &mut Scanner,
+        window: Duration,
+    ) -> ProcResult<()> {
+        let stretches = {
+            let uffds = self.uffds.lock().unwrap();
+            let Some(watched) = uffds.get(&tid) else {
+                return Ok(());
+            };
+            tracked_stretches(watched)
+        };
+
+        let p = Process::new(tid.0 as _)?;
+        let now = Instant::now();
+        let mut dirty = vec![];
+        for (stretch, _) in stretches {
+            dirty.extend(scanner.soft_dirty(&p, stretch)?);
+        }
+        // "4" clears soft-dirty bits, for the whole process
+        std::fs::write(format!("/proc/{}/clear_refs", tid.0), "4")?;
+        let mappings: Vec<_> = p
+            .maps()?
+            .iter()
+            .map(|map| (map.address.0..map.address.1, mapping_name(&map.pathname)))
+            .collect();
+
+        let working_set = {
+            let mut uffds = self.uffds.lock().unwrap();
+            let Some(watched) = uffds.get_mut(&tid) else {
+                return Ok(());
+            };
+            for range in dirty {
+                watched.written.insert(range, now);
+            }
+
+            // forget what's too old, and what's not tracked anymore
+            let mut written = RangeSet::new();
+            let mut stale = vec![];
+            for (range, at) in watched.written.iter() {
+                let tracked = watched
+                    .reported
+                    .overlapping(range)
+                    .any(|(_, state)| *state != MemState::Untracked);
+                if now.duration_since(*at) > window || !tracked {
+                    stale.push(range.clone());
+                } else {
+                    written.insert(range.clone());
+                }
+            }
+            for range in stale {
+                watched.written.remove(range);
+            }
+
+            let working_set = WorkingSet {
+                window_secs: window.as_secs(),
+                per_mapping: per_mapping(&mappings, &written),
+                written,
+            };
+            if watched.working_set.as_ref() == Some(&working_set) {
+                return Ok(());
+            }
+            watched.working_set = Some(working_set.clone());
+            working_set
+        };
+        self.tx
+            .send(MeviEvent::TraceeEvent(
+                tid,
+                TraceePayload::WorkingSet(working_set),
+            ))
+            .unwrap();
+        Ok(())
+    }
+
+    fn run(&self) {
+        let tx = &self.tx;
+        let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as u64;
+        let mut ready = vec![EpollEvent::empty(); EVENT_BATCH];
+        let mut buf = EventBuffer::new(EVENT_BATCH);
+        let mut faults = Faults {
+            page_size,
+            fault_around: self.fault_around,
+            track_writes: self.track_writes,
+            wakes: vec![],
+            zeroes: vec![],
+        };
+
+        let mut payloads = vec![];
+
+        loop {
+            let n = match self.epoll.wait(&mut ready, -1) {
+                Ok(n) => n,
+                Err(Errno::EINTR) => continue,
+                Err(e) => panic!("epoll_wait failed: {e:?}"),
+            };
+
+            for ev in &ready[..n] {
+                let tid = TraceeId(ev.data());
+                let alive = {
+                    let mut uffds = self.uffds.lock().unwrap();
+                    let Some(watched) = uffds.get_mut(&tid) else {
+                        // removed while we were waiting
+                        continue;
+                    };
+
+                    let alive = drain(tid, watched, &mut buf, &mut faults, &mut payloads);
+                    if !alive {
+                        if let Some(watched) = uffds.remove(&tid) {
+                            _ = self.epoll.delete(borrow(&watched.uffd));
+                        }
+                        self.unlink(&mut uffds, tid);
+                    }
+                    alive
+                };
+
+                self.send_all(tid, std::mem::take(&mut payloads));
+                if !alive {
+                    tx.send(MeviEvent::TraceeEvent(tid, TraceePayload::Exit))
+                        .unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Contiguous stretches of tracked memory with the same backing, so pagemap
+/// is read in as few chunks as possible.
+fn tracked_stretches(watched: &Watched) -> Vec<(Range<u64>, Backing)> {
+    let mut stretches: Vec<(Range<u64>, Backing)> = vec![];
+    for (range, state) in watched.reported.iter() {
+        if *state == MemState::Untracked {
+            continue;
+        }
+        let backing = backing_at(&watched.backings, range.start);
+        match stretches.last_mut() {
+            Some((last, last_backing)) if last.end == range.start && *last_backing == backing => {
+                last.end = range.end
+            }
+            _ => stretches.push((range.clone(), backing)),
+        }
+    }
+    stretches
+}
+
+/// What to report for memory we said was `known`, if pagemap says it's
+/// `actual`.
+fn correction(known: MemState, actual: MemState, sees_thps: bool) -> Option<MemState> {
+    match (known, actual) {
+        (MemState::Untracked, _) => None,
+        // prefaulted pages are zero pages until written to
+        (MemState::Prefaulted, MemState::ZeroPage) => None,
+        // pagemap can't tell THPs from other resident pages without kpageflags
+        (MemState::HugeResident, MemState::Resident) if !sees_thps => None,
+        // nor lazily freed pages, until they're reclaimed
+        (MemState::LazyFree, MemState::Resident | MemState::HugeResident) => None,
+        // the zero page isn't exclusively mapped either
+        (MemState::Prefaulted | MemState::ZeroPage, MemState::SharedCow) => None,
+        (known, actual) if known == actual => None,
+        (_, actual) => Some(actual),
+    }
+}
+
+fn borrow(uffd: &Uffd) -> BorrowedFd<'_> {
+    unsafe { BorrowedFd::borrow_raw(uffd.as_raw_fd()) }
+}
+
+/// Asks the kernel which uffd features it supports, with a throwaway uffd
+/// of our own: UFFDIO_API fails outright if a tracee's uffd asks for too
+/// much, and it can't be retried on the same uffd.
+fn supported_features() -> FeatureFlags {
+    // user mode only doesn't need `vm.unprivileged_userfaultfd`, but it
+    // doesn't exist before Linux 5.11
+    for flags in [
+        libc::O_CLOEXEC as u64 | UFFD_USER_MODE_ONLY,
+        libc::O_CLOEXEC as u64,
+    ] {
+        let ret = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+        if ret < 0 {
+            continue;
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(ret as _) };
+
+        // with no features requested, the kernel reports all it has
+        let mut api = raw::uffdio_api {
+            api: raw::UFFD_API,
+            features: 0,
+            ioctls: 0,
+        };
+        let ret = unsafe {
+            libc::ioctl(
+                fd.as_raw_fd(),
+                raw::UFFDIO_API as _,
+                &mut api as *mut raw::uffdio_api,
+            )
+        };
+        if ret == 0 {
+            return FeatureFlags::from_bits_truncate(api.features);
+        }
+    }
+    warn!("couldn't probe uffd features, sticking to the basics");
+    FeatureFlags::empty()
+}
+
+/// How page faults get resolved, and the threads waiting for it
+struct Faults {
+    page_size: u64,
+    fault_around: u64,
+    /// Whether pages are registered for write-protect faults, and pages
+    /// filled in for a read should be write-protected.
+    track_writes: bool,
+    /// Pages we filled without waking up whoever faulted on them, to wake
+    /// them all up at once after a batch of events.
+    wakes: Vec<Range<u64>>,
+    /// Source for filling in huge pages, as big as the largest one so far
+    zeroes: Vec<u8>,
+}
+
+impl Faults {
+    /// Fills in the page at `addr` and up to `fault_around - 1` pages after
+    /// it. Returns how many bytes were filled, if any.
+    fn resolve(&mut self, uffd: &Uffd, addr: u64) -> Result<u64, Errno> {
+        let mut len = self.fault_around * self.page_size;
+        loop {
+            match zeropage_dontwake(uffd, addr, len) {
+                Ok(filled) => {
+                    self.wakes.push(addr..addr + self.page_size);
+                    return Ok(filled);
+                }
+                // the range spills past the end of the mapping (or of what's
+                // registered), try a smaller one
+                Err(Errno::ENOENT) if len > self.page_size => {
+                    len = (len / self.page_size / 2).max(1) * self.page_size;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Fills in the huge page that `addr` is in with zeroes.
+    fn resolve_huge(&mut self, uffd: &Uffd, addr: u64, page_size: u64) -> Result<(), Errno> {
+        if (self.zeroes.len() as u64) < page_size {
+            // calloc'd, so it doesn't cost anything until it's read
+            self.zeroes = vec![0; page_size as usize];
+        }
+        let addr = addr & !(page_size - 1);
+        copy_dontwake(uffd, self.zeroes.as_ptr() as u64, addr, page_size)?;
+        self.wakes.push(addr..addr + page_size);
+        Ok(())
+    }
+
+    /// Wakes every thread waiting on a page we filled, merging adjacent
+    /// ranges so it's usually just a couple ioctls.
+    fn wake_all(&mut self, tid: TraceeId, uffd: &Uffd) {
+        self.wakes.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = vec![];
+        for range in self.wakes.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        for range in merged {
+            if let Err(e) = uffd.wake(range.start as _, (range.end - range.start) as _) {
+                warn!("{tid} failed to wake {range:x?}: {e:?}");
+            }
+        }
+    }
+}
+
+/// Maps the page at `start`, which is already in the page cache, without
+/// waking the faulting thread.
+fn continue_dontwake(uffd: &Uffd, start: u64, len: u64) -> Result<(), Errno> {
+    let mut cont = raw::uffdio_continue {
+        range: raw::uffdio_range { start, len },
+        mode: raw::UFFDIO_CONTINUE_MODE_DONTWAKE as _,
+        mapped: 0,
+    };
+    let ret = unsafe {
+        libc::ioctl(
+            uffd.as_raw_fd(),
+            raw::UFFDIO_CONTINUE as _,
+            &mut cont as *mut raw::uffdio_continue,
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+/// Copies `len` bytes from our `src` to the tracee's `dst`, without waking
+/// the faulting thread.
+fn copy_dontwake(uffd: &Uffd, src: u64, dst: u64, len: u64) -> Result<(), Errno> {
+    let mut copy = raw::uffdio_copy {
+        dst,
+        src,
+        len,
+        mode: raw::UFFDIO_COPY_MODE_DONTWAKE as _,
+        copy: 0,
     };
+    let ret = unsafe {
+        libc::ioctl(
+            uffd.as_raw_fd(),
+            raw::UFFDIO_COPY as _,
+            &mut copy as *mut raw::uffdio_copy,
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+/// Zero-fills `len` bytes at `start` without waking the faulting thread.
+/// Returns how many bytes were filled, which is fewer than asked for if it
+/// ran into a page that was already there.
+fn zeropage_dontwake(uffd: &Uffd, start: u64, len: u64) -> Result<u64, Errno> {
+    let mut zeropage = raw::uffdio_zeropage {
+        range: raw::uffdio_range { start, len },
+        mode: raw::UFFDIO_ZEROPAGE_MODE_DONTWAKE as _,
+        zeropage: 0,
+    };
+    let ret = unsafe {
+        libc::ioctl(
+            uffd.as_raw_fd(),
+            raw::UFFDIO_ZEROPAGE as _,
+            &mut zeropage as *mut raw::uffdio_zeropage,
+        )
+    };
+    // partial progress fails with EAGAIN, but `zeropage` tells how far it got
+    match zeropage.zeropage {
+        n if n > 0 => Ok(n as u64),
+        n if n < 0 => Err(Errno::from_i32(-n as i32)),
+        _ => Errno::result(ret).map(|_| 0),
+    }
+}
 
+/// Handles events until there are none left, applying them to
+/// `watched.reported` and adding them to `payloads` for the relay. Returns
+/// false if the uffd is unusable and should be forgotten.
+fn drain(
+    tid: TraceeId,
+    watched: &mut Watched,
+    buf: &mut EventBuffer,
+    faults: &mut Faults,
+    payloads: &mut Vec<TraceePayload>,
+) -> bool {
     loop {
-        let event = match uffd.read_event() {
-            Ok(event) => event.unwrap(),
+        let uffd = &watched.uffd;
+        let events = match uffd.read_events(buf) {
+            Ok(events) => events,
             Err(userfaultfd::Error::SystemError(nix::Error::EBADF)) => {
                 warn!("{tid} uffd {} died! (got EBADF)", uffd.as_raw_fd());
-                let ev = MeviEvent::TraceeEvent(tid, TraceePayload::Exit);
-                tx.send(ev).unwrap();
-                return;
+                return false;
             }
             Err(e) => {
-                panic!("uffd.read_event failed: {e:?}");
+                warn!(
+                    "{tid} couldn't read events from uffd {}, dropping it: {e:?}",
+                    uffd.as_raw_fd()
+                );
+                return false;
             }
         };
-        tracing::debug!("{tid} got {event:?}");
-        match event {
-            userfaultfd::Event::Pagefault { addr, .. } => {
-                let res = unsafe { uffd.zeropage(addr, page_size as _, true) };
-                if let Err(e) = res {
-                    let errno = match e {
-                        userfaultfd::Error::ZeropageFailed(errno) => errno,
-                        _ => unreachable!(),
-                    };
 
-                    match errno as i32 {
-                        libc::EAGAIN => {
-                            // retrying often doesn't work, BUT this means the
-                            // thread wasn't awakened, so we need to do it by
-                            // hand. worst case scenario, we get another event
-                            // from the same range.
-                            debug!("zeropage({addr:p}, {page_size:x?}) = EAGAIN, breaking");
-                            uffd.wake(addr, page_size as _).unwrap();
-                        }
-                        libc::EBADF => {
-                            warn!("{tid} uffd {} died! (got EBADF)", uffd.as_raw_fd());
-                            return;
-                        }
-                        libc::ENOENT => {
-                            // not sure if this is fine but let's not panic?
-                            warn!("{tid} ENOENT while zeropaging {addr:?}");
-                            continue;
-                        }
-                        _ => {
-                            panic!("while doing zeropage: {e:?}");
-                        }
-                    }
+        let mut got_any = false;
+        let mut alive = true;
+        let events = events.collect::<Vec<_>>();
+        for event in events {
+            got_any = true;
+            if !handle(tid, watched, event.unwrap(), faults, payloads) {
+                alive = false;
+                break;
+            }
+        }
+        faults.wake_all(tid, &watched.uffd);
+        if !alive {
+            return false;
+        }
+        if !got_any {
+            // that's EAGAIN, wait for epoll to tell us about more
+            return true;
+        }
+    }
+}
+
+/// Which thread of `tid` faulted. The kernel only says if we asked for
+/// [FeatureFlags::THREAD_ID], otherwise we blame the main thread.
+///
+/// `None` if it's not one of the tracee's threads: mevi faults pages in too,
+/// when it reads them (see [crate::remote]).
+fn faulting_thread(
+    tid: TraceeId,
+    threads: &mut HashSet<TraceeId>,
+    thread_id: Option<ThreadId>,
+) -> Option<TraceeId> {
+    let Some(thread_id) = thread_id else {
+        return Some(tid);
+    };
+    let thread = TraceeId(thread_id.0 as _);
+    if threads.contains(&thread) {
+        return Some(thread);
+    }
+    let ours = thread.0 != 0
+        && std::path::Path::new(&format!("/proc/{}/task/{}", tid.0, thread.0)).exists();
+    if !ours {
+        debug!("{tid} had a page faulted in by {thread}, which isn't one of its threads");
+        return None;
+    }
+    threads.insert(thread);
+    Some(thread)
+}
+
+fn handle(
+    tid: TraceeId,
+    watched: &mut Watched,
+    event: userfaultfd::Event,
+    faults: &mut Faults,
+    payloads: &mut Vec<TraceePayload>,
+) -> bool {
+    let Watched {
+        uffd,
+        backings,
+        reported,
+        threads,
+        ..
+    } = watched;
+    let mut send_ev = |payload: TraceePayload| {
+        payload.apply_to_memmap(reported);
+        payloads.push(payload);
+    };
+
+    tracing::debug!("{tid} got {event:?}");
+    match event {
+        userfaultfd::Event::Pagefault {
+            kind: FaultKind::WriteProtected,
+            addr,
+            ..
+        } => {
+            // first write to a page that was only read so far: let it
+            // through, it gets copied out of the zero page.
+            let addr = addr as u64;
+            let page_size = faults.page_size;
+            if let Err(e) = uffd.remove_write_protection(addr as _, page_size as _, false) {
+                warn!("{tid} failed to unprotect {addr:x?}: {e:?}");
+            }
+            faults.wakes.push(addr..addr + page_size);
+            send_ev(TraceePayload::MemStateChange {
+                range: addr..addr + page_size,
+                state: MemState::Resident,
+            });
+        }
+        userfaultfd::Event::Pagefault {
+            kind: FaultKind::Minor,
+            addr,
+            thread_id,
+            ..
+        } => {
+            // the page is in the page cache already, but this process hasn't
+            // touched it yet: just map it.
+            let page_size = match backing_at(backings, addr as u64) {
+                Backing::Hugetlb { page_size } => page_size,
+                _ => faults.page_size,
+            };
+            let addr = addr as u64 & !(page_size - 1);
+            match continue_dontwake(uffd, addr, page_size) {
+                Ok(()) => {}
+                Err(Errno::EBADF) => {
+                    warn!("{tid} uffd {} died! (got EBADF)", uffd.as_raw_fd());
+                    return false;
                 }
-                let addr = addr as u64;
-                send_ev(TraceePayload::MemStateChange {
+                Err(Errno::EEXIST) | Err(Errno::EAGAIN) => {
+                    // another thread got there first, or the address space
+                    // is changing: either way, wake it and let it fault again
+                    // if it needs to.
+                    faults.wakes.push(addr..addr + page_size);
+                    return true;
+                }
+                Err(e @ (Errno::ENOENT | Errno::EFAULT)) => {
+                    // unmapped or truncated while we weren't looking
+                    let message = format!("{e} while mapping {addr:x?} from the page cache");
+                    warn!("{tid} {message}");
+                    send_ev(TraceePayload::Warning { message });
+                    return true;
+                }
+                Err(e) => {
+                    panic!("while doing continue at {addr:x?}: {e:?}");
+                }
+            }
+            faults.wakes.push(addr..addr + page_size);
+            send_ev(TraceePayload::PageFault {
+                range: addr..addr + page_size,
+                state: if page_size > faults.page_size {
+                    MemState::HugeResident
+                } else {
+                    MemState::Resident
+                },
+                thread: faulting_thread(tid, threads, thread_id),
+            });
+        }
+        userfaultfd::Event::Pagefault {
+            addr,
+            rw,
+            thread_id,
+            ..
+        } => {
+            let addr = addr as u64;
+            let backing = backing_at(backings, addr);
+            let (page_size, filled) = match backing {
+                Backing::Hugetlb { page_size } => (
+                    page_size,
+                    faults
+                        .resolve_huge(uffd, addr, page_size)
+                        .map(|()| page_size),
+                ),
+                _ => (faults.page_size, faults.resolve(uffd, addr)),
+            };
+            let addr = addr & !(page_size - 1);
+            let filled = match filled {
+                Ok(filled) => filled,
+                Err(Errno::EAGAIN) => {
+                    // the address space is changing under us, retrying often
+                    // doesn't work, BUT this means the thread wasn't
+                    // awakened, so we need to do it by hand. worst case
+                    // scenario, we get another event from the same range.
+                    debug!("zeropage({addr:x?}, {page_size:x?}) = EAGAIN, breaking");
+                    faults.wakes.push(addr..addr + page_size);
+                    return true;
+                }
+                Err(Errno::EEXIST) => {
+                    // another thread faulted on the same page, and we already
+                    // filled it in.
+                    faults.wakes.push(addr..addr + page_size);
+                    return true;
+                }
+                Err(Errno::EBADF) => {
+                    warn!("{tid} uffd {} died! (got EBADF)", uffd.as_raw_fd());
+                    return false;
+                }
+                Err(Errno::ENOENT) => {
+                    // not sure if this is fine but let's not panic?
+                    warn!("{tid} ENOENT while filling in {addr:x?}");
+                    return true;
+                }
+                Err(Errno::EFAULT) => {
+                    let message = format!("EFAULT while filling in {addr:x?}");
+                    warn!("{tid} {message}");
+                    send_ev(TraceePayload::Warning { message });
+                    return true;
+                }
+                Err(e) => {
+                    panic!("while doing zeropage: {e:?}");
+                }
+            };
+
+            if let Backing::Hugetlb { .. } = backing {
+                send_ev(TraceePayload::PageFault {
                     range: addr..addr + page_size,
-                    state: MemState::Resident,
+                    state: MemState::HugeResident,
+                    thread: faulting_thread(tid, threads, thread_id),
                 });
+                return true;
             }
-            userfaultfd::Event::Remap { from, to, len } => {
-                let from = from as usize;
-                let to = to as usize;
 
-                debug!(
-                    "{} got uffd remap event {:x?}.. => {:x?}, len = {}",
-                    tid,
-                    from,
-                    to,
-                    make_format(BINARY)(len),
-                );
+            let written = rw == ReadWrite::Write;
+            // shmem pages are real pages even when zero-filled
+            let zero_page = backing == Backing::Anon;
+            if faults.track_writes && zero_page {
+                // a write gets its own copy of the zero page right away, but
+                // otherwise we want to know when that happens.
+                let protect = if written {
+                    addr + page_size..addr + filled
+                } else {
+                    addr..addr + filled
+                };
+                if protect.start < protect.end {
+                    if let Err(e) =
+                        uffd.write_protect(protect.start as _, (protect.end - protect.start) as _)
+                    {
+                        warn!("{tid} failed to write-protect {protect:x?}: {e:?}");
+                    }
+                }
             }
-            userfaultfd::Event::Remove { start, end } => {
-                let start = start as usize;
-                let end = end as usize;
 
-                debug!(
-                    "{} got uffd remove event {:x?}, len = {}",
-                    tid,
-                    start..end,
-                    make_format(BINARY)(end - start),
-                );
+            send_ev(TraceePayload::PageFault {
+                range: addr..addr + page_size,
+                state: if faults.track_writes && zero_page && !written {
+                    MemState::ZeroPage
+                } else {
+                    MemState::Resident
+                },
+                thread: faulting_thread(tid, threads, thread_id),
+            });
+            if filled > page_size {
+                // those weren't touched by anyone yet. the zero page costs
+                // nothing, but shmem gets real pages right away.
+                send_ev(TraceePayload::MemStateChange {
+                    range: addr + page_size..addr + filled,
+                    state: if zero_page {
+                        MemState::Prefaulted
+                    } else {
+                        MemState::Resident
+                    },
+                });
             }
-            userfaultfd::Event::Unmap { start, end } => {
-                let start = start as usize;
-                let end = end as usize;
+        }
+        // unlike what ptrace sees, those are ordered with respect to page
+        // faults, so they're the source of truth for registered ranges.
+        // the tracer only reports what's outside of those.
+        userfaultfd::Event::Remap { from, to, len } => {
+            let from = from as u64;
+            let to = to as u64;
+            let len = len as u64;
 
-                debug!(
-                    "{} got uffd unmap event {:x?}, len = {}",
-                    tid,
-                    start..end,
-                    make_format(BINARY)(end - start),
-                );
+            debug!(
+                "{} got uffd remap event {:x?}.. => {:x?}, len = {}",
+                tid,
+                from,
+                to,
+                make_format(BINARY)(len),
+            );
+            if let Some(backing) = backings.get(&from).copied() {
+                backings.remove(from..from + len);
+                backings.insert(to..to + len, backing);
             }
-            other => {
-                warn!("unhandled uffd event: {:?}", other);
+            send_ev(TraceePayload::Remap {
+                old_range: from..from + len,
+                new_range: to..to + len,
+            });
+        }
+        userfaultfd::Event::Remove { start, end } => {
+            let start = start as u64;
+            let end = end as u64;
+
+            debug!(
+                "{} got uffd remove event {:x?}, len = {}",
+                tid,
+                start..end,
+                make_format(BINARY)(end - start),
+            );
+            send_ev(TraceePayload::MemStateChange {
+                range: start..end,
+                state: MemState::NotResident,
+            });
+        }
+        userfaultfd::Event::Unmap { start, end } => {
+            let start = start as u64;
+            let end = end as u64;
+
+            debug!(
+                "{} got uffd unmap event {:x?}, len = {}",
+                tid,
+                start..end,
+                make_format(BINARY)(end - start),
+            );
+            if start < end {
+                backings.remove(start..end);
+                send_ev(TraceePayload::Unmap { range: start..end });
             }
         }
+        other => {
+            warn!("unhandled uffd event: {:?}", other);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MemState::*;
+
+    #[test]
+    fn corrects_what_pagemap_contradicts() {
+        assert_eq!(correction(NotResident, Resident, true), Some(Resident));
+        assert_eq!(correction(Resident, Swapped, true), Some(Swapped));
+        assert_eq!(correction(Resident, NotResident, false), Some(NotResident));
+        assert_eq!(correction(Prefaulted, Resident, false), Some(Resident));
+        assert_eq!(correction(LazyFree, NotResident, false), Some(NotResident));
+        assert_eq!(correction(Resident, SharedCow, false), Some(SharedCow));
+    }
+
+    #[test]
+    fn leaves_what_pagemap_cant_tell_apart() {
+        for actual in [Resident, NotResident, Swapped] {
+            assert_eq!(correction(Untracked, actual, true), None);
+        }
+        for state in [Resident, NotResident, HugeResident, SharedCow] {
+            assert_eq!(correction(state, state, true), None);
+        }
+        assert_eq!(correction(Prefaulted, ZeroPage, true), None);
+        assert_eq!(correction(LazyFree, Resident, true), None);
+        assert_eq!(correction(LazyFree, HugeResident, true), None);
+        assert_eq!(correction(Prefaulted, SharedCow, false), None);
+        assert_eq!(correction(ZeroPage, SharedCow, false), None);
+    }
+
+    #[test]
+    fn thps_only_corrected_when_visible() {
+        assert_eq!(correction(HugeResident, Resident, false), None);
+        assert_eq!(correction(HugeResident, Resident, true), Some(Resident));
+        assert_eq!(
+            correction(Resident, HugeResident, false),
+            Some(HugeResident)
+        );
     }
 }